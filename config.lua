@@ -5,6 +5,12 @@ nwm.set.launcher("dmenu_run")
 nwm.set.border_width(2)
 nwm.set.border_active_color("#ffdd33")
 nwm.set.border_inactive_color("#181818")
+nwm.set.layout(nwm.layout.horizontal)
+nwm.set.master_ratio(0.55)
+nwm.set.master_count(1)
+nwm.set.auto_reload(true)
+nwm.set.workspace_layout(1, nwm.layout.master_stack)
+nwm.set.workspace_names({ "term", "web", "code" })
 
 nwm.bind("h", nwm.action.focus.left)
 nwm.bind("l", nwm.action.focus.right)
@@ -17,6 +23,10 @@ nwm.bind("Space", nwm.action.launcher)
 nwm.bind("Return", nwm.action.terminal)
 
 nwm.bind("w", nwm.action.close)
+nwm.bind("Shift-w", nwm.action.kill)
+nwm.bind("f", nwm.action.fullscreen)
+nwm.bind("Tab", nwm.action.layout.next)
+nwm.bind("Shift-Tab", nwm.action.layout.prev)
 -- nwm.bind("2", nwm.action.next_ws)
 -- nwm.bind("1", nwm.action.prev_ws)
 
@@ -43,6 +53,11 @@ nwm.bind("Shift-9", nwm.action.move_to_ws8)
 nwm.bind("Shift-0", nwm.action.move_to_ws9)
 
 nwm.bind("r", nwm.action.reload)
+nwm.bind("Shift-r", nwm.action.restart)
+
+nwm.bind("p", function()
+    nwm.spawn("notify-send \"nwm\" \"workspace " .. nwm.state.workspace .. ", layout " .. nwm.state.layout .. "\"")
+end)
 
 if nwm.first_boot then
     os.execute("pipewire &")
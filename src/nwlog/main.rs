@@ -4,10 +4,15 @@ use std::ffi::CString;
 fn main() -> nix::Result<()> {
     let cmd = CString::new("tail").unwrap();
 
+    // Same default as nwm's --log-file
+    let path = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| String::from("/tmp/nwm.log"));
+
     let args = vec![
         CString::new("tail").unwrap(),
         CString::new("-F").unwrap(),
-        CString::new("/tmp/nwm.log").unwrap(),
+        CString::new(path).unwrap(),
     ];
 
     execvp(&cmd, &args)?;
@@ -1,17 +1,25 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, VecDeque},
+    os::fd::{AsFd, BorrowedFd},
+    time::{Duration, Instant},
+};
 
-use log::{error, warn};
+use log::{error, info, warn};
+use nix::poll::{PollFd, PollFlags, PollTimeout, poll};
 
 use x11rb::{
     connection::Connection,
+    errors::ConnectionError,
     protocol::{
         Event,
         xproto::{
-            Atom, ChangeWindowAttributesAux, ConfigureWindowAux, ConnectionExt, EventMask,
-            GrabMode, InputFocus, Keycode, MappingNotifyEvent, ModMask, Screen, StackMode, Time,
+            Atom, AtomEnum, ChangeWindowAttributesAux, ClientMessageEvent, ConfigureWindowAux,
+            ConnectionExt, CreateWindowAux, EventMask, GrabMode, InputFocus, Keycode,
+            MappingNotifyEvent, ModMask, PropMode, Screen, StackMode, Time, WindowClass,
         },
     },
     rust_connection::RustConnection,
+    wrapper::ConnectionExt as _,
 };
 
 pub type WindowId = u32;
@@ -21,32 +29,34 @@ pub struct X11RB {
     screen: Screen,
     pointer_pos: (i16, i16),
     keymap: HashMap<u32, Keycode>,
+    screen_number: usize,
+    /// Owner of the `WM_S<screen>` selection, as long as it exists nwm is the window manager
+    wm_window: WindowId,
+    wm_selection: Atom,
+    /// Events that arrived while waiting for a specific one (see `acquire_wm_selection`), handed
+    /// out by `poll_event` before anything new
+    pending: VecDeque<Event>,
 }
 
+/// How long `--replace` waits for the old window manager to exit before killing it
+const REPLACE_TIMEOUT: Duration = Duration::from_secs(5);
+
 impl X11RB {
-    pub fn init() -> Option<Self> {
-        let (conn, screen_number) = x11rb::connect(None)
+    /// Connects to the display and becomes its window manager, `replace` takes over from a
+    /// running one instead of refusing to start
+    pub fn init(display_name: &str, replace: bool) -> Option<Self> {
+        let (conn, screen_number) = x11rb::connect(Some(display_name))
             .map_err(|e| {
-                error!("Failed to open x11 display: {e}");
+                error!("Failed to open x11 display {display_name}: {e}");
             })
             .ok()?;
         let screen = &conn.setup().roots[screen_number];
         let root = screen.root;
 
-        let event_mask = EventMask::SUBSTRUCTURE_REDIRECT
-            | EventMask::SUBSTRUCTURE_NOTIFY
-            | EventMask::KEY_PRESS
-            | EventMask::POINTER_MOTION
-            | EventMask::PROPERTY_CHANGE
-            | EventMask::ENTER_WINDOW
-            | EventMask::LEAVE_WINDOW;
-
-        conn.change_window_attributes(
-            root,
-            &ChangeWindowAttributesAux::new().event_mask(event_mask),
-        )
-        .map_err(|e| error!("Failed to set window event mask: {e}"))
-        .ok()?;
+        let mut pending = VecDeque::new();
+        let (wm_window, wm_selection) =
+            Self::acquire_wm_selection(&conn, screen_number, root, replace, &mut pending)?;
+        Self::select_root_events(&conn, root)?;
         x11rb::protocol::randr::query_version(&conn, 1, 5).unwrap().reply().unwrap();
 
         x11rb::protocol::randr::select_input(
@@ -60,12 +70,219 @@ impl X11RB {
             conn,
             pointer_pos: (0, 0),
             keymap: HashMap::new(),
+            screen_number,
+            wm_window,
+            wm_selection,
+            pending,
         };
         wm.rebuild_keymap();
 
         Some(wm)
     }
 
+    /// Only one client can redirect the root's substructure, so this fails when another window
+    /// manager is running
+    fn select_root_events(conn: &RustConnection, root: WindowId) -> Option<()> {
+        let event_mask = EventMask::SUBSTRUCTURE_REDIRECT
+            | EventMask::SUBSTRUCTURE_NOTIFY
+            | EventMask::KEY_PRESS
+            | EventMask::POINTER_MOTION
+            | EventMask::PROPERTY_CHANGE
+            | EventMask::ENTER_WINDOW
+            | EventMask::LEAVE_WINDOW;
+
+        conn.change_window_attributes(
+            root,
+            &ChangeWindowAttributesAux::new().event_mask(event_mask),
+        )
+        .map_err(|e| error!("Failed to set window event mask: {e}"))
+        .ok()?
+        .check()
+        .map_err(|e| {
+            error!(
+                "Another window manager is already running (can't redirect the root window: {e})"
+            )
+        })
+        .ok()
+    }
+
+    /// ICCCM 2.8 manager selection: takes `WM_S<screen>` (waiting for the previous owner to exit
+    /// when replacing it) and announces it with a `MANAGER` client message. Unrelated events read
+    /// meanwhile are pushed to `pending`
+    fn acquire_wm_selection(
+        conn: &RustConnection,
+        screen_number: usize,
+        root: WindowId,
+        replace: bool,
+        pending: &mut VecDeque<Event>,
+    ) -> Option<(WindowId, Atom)> {
+        let name = format!("WM_S{screen_number}");
+        let atom = |name: &str| {
+            conn.intern_atom(false, name.as_bytes())
+                .ok()?
+                .reply()
+                .map_err(|e| error!("Failed to intern {name}: {e}"))
+                .ok()
+                .map(|r| r.atom)
+        };
+        let selection = atom(&name)?;
+        let manager = atom("MANAGER")?;
+
+        let owner = conn
+            .get_selection_owner(selection)
+            .ok()?
+            .reply()
+            .map_err(|e| error!("Failed to get the owner of {name}: {e}"))
+            .ok()?
+            .owner;
+        if owner != x11rb::NONE && !replace {
+            error!(
+                "Another window manager is running (it owns {name}), use --replace to replace it"
+            );
+            return None;
+        }
+
+        let window = conn
+            .generate_id()
+            .map_err(|e| error!("Failed to generate a window id: {e}"))
+            .ok()?;
+        conn.create_window(
+            x11rb::COPY_DEPTH_FROM_PARENT,
+            window,
+            root,
+            -1,
+            -1,
+            1,
+            1,
+            0,
+            WindowClass::INPUT_ONLY,
+            x11rb::COPY_FROM_PARENT,
+            &CreateWindowAux::new()
+                .override_redirect(1)
+                .event_mask(EventMask::PROPERTY_CHANGE),
+        )
+        .map_err(|e| error!("Failed to create the selection owner window: {e}"))
+        .ok()?;
+        if owner != x11rb::NONE {
+            _ = conn.change_window_attributes(
+                owner,
+                &ChangeWindowAttributesAux::new().event_mask(EventMask::STRUCTURE_NOTIFY),
+            );
+        }
+
+        // Selections can't be taken with CurrentTime, a zero length property change gets us a
+        // timestamp from the server
+        conn.change_property8(
+            PropMode::APPEND,
+            window,
+            AtomEnum::WM_NAME,
+            AtomEnum::STRING,
+            &[],
+        )
+        .ok()?;
+        conn.flush().ok()?;
+        let time = loop {
+            match conn.wait_for_event() {
+                Ok(Event::PropertyNotify(e)) if e.window == window => break e.time,
+                Ok(e) => pending.push_back(e),
+                Err(e) => {
+                    error!("Failed to wait for a timestamp: {e}");
+                    return None;
+                }
+            }
+        };
+
+        conn.set_selection_owner(window, selection, time).ok()?;
+        let new_owner = conn
+            .get_selection_owner(selection)
+            .ok()?
+            .reply()
+            .ok()?
+            .owner;
+        if new_owner != window {
+            error!("Failed to take {name}, another window manager got it first");
+            return None;
+        }
+
+        if owner != x11rb::NONE {
+            info!("Waiting for the window manager owning {name} to exit");
+            Self::wait_for_destroy(conn, owner, pending);
+        }
+
+        let event = ClientMessageEvent::new(32, root, manager, [time, selection, window, 0, 0]);
+        conn.send_event(false, root, EventMask::STRUCTURE_NOTIFY, event)
+            .ok()?;
+        Some((window, selection))
+    }
+
+    /// Waits for the old selection owner's window to go away, killing its client if it takes
+    /// longer than `REPLACE_TIMEOUT`. Other events are pushed to `pending`
+    fn wait_for_destroy(conn: &RustConnection, window: WindowId, pending: &mut VecDeque<Event>) {
+        let deadline = Instant::now() + REPLACE_TIMEOUT;
+        loop {
+            while let Ok(Some(e)) = conn.poll_for_event() {
+                match e {
+                    Event::DestroyNotify(e) if e.window == window => return,
+                    e => pending.push_back(e),
+                }
+            }
+
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                warn!("The old window manager didn't exit in time, killing it");
+                _ = conn.kill_client(window);
+                _ = conn.flush();
+                return;
+            }
+            _ = conn.flush();
+            let mut fds = [PollFd::new(conn.stream().as_fd(), PollFlags::POLLIN)];
+            _ = poll(
+                &mut fds,
+                PollTimeout::try_from(left.as_millis() + 1).unwrap_or(PollTimeout::MAX),
+            );
+        }
+    }
+
+    /// Stops being the window manager (releasing the root window and `WM_S<screen>`) so another
+    /// process can take over right away
+    pub fn release_wm(&mut self) -> Option<()> {
+        let root = self.root_window();
+        self.conn
+            .change_window_attributes(
+                root,
+                &ChangeWindowAttributesAux::new().event_mask(EventMask::NO_EVENT),
+            )
+            .ok()?;
+        self.conn.destroy_window(self.wm_window).ok()?;
+        // Round trip, so the server is done with both before we go on
+        self.conn.get_input_focus().ok()?.reply().ok()?;
+        Some(())
+    }
+
+    /// Undoes `release_wm`
+    pub fn reacquire_wm(&mut self) -> Option<()> {
+        let root = self.root_window();
+        let (window, selection) = Self::acquire_wm_selection(
+            &self.conn,
+            self.screen_number,
+            root,
+            false,
+            &mut self.pending,
+        )?;
+        self.wm_window = window;
+        self.wm_selection = selection;
+        Self::select_root_events(&self.conn, root)
+    }
+
+    pub fn wm_selection(&self) -> Atom {
+        self.wm_selection
+    }
+
+    /// Lives as long as nwm is the window manager, doubles as the `_NET_SUPPORTING_WM_CHECK` window
+    pub fn wm_window(&self) -> WindowId {
+        self.wm_window
+    }
+
     pub fn root_window(&self) -> u32 {
         self.screen.root
     }
@@ -119,6 +336,7 @@ impl X11RB {
         Some(())
     }
 
+    #[allow(dead_code)]
     pub fn grab_pointer(&mut self) -> Option<()> {
         self.conn
             .grab_pointer(
@@ -139,7 +357,7 @@ impl X11RB {
         Some(())
     }
 
-    fn flush(&mut self) -> Option<()> {
+    pub fn flush(&mut self) -> Option<()> {
         self.conn
             .flush()
             .map_err(|e| {
@@ -148,15 +366,21 @@ impl X11RB {
             .ok()
     }
 
-    pub fn next_event(&mut self) -> Option<Event> {
-        self.conn.flush().unwrap();
-        let e = self
-            .conn
-            .wait_for_event()
-            .map_err(|e| {
-                warn!("Failed to get the next x11 event: {e}");
-            })
-            .ok()?;
+    /// The socket of the x11 connection, for waiting on it together with other files
+    pub fn fd(&self) -> BorrowedFd<'_> {
+        self.conn.stream().as_fd()
+    }
+
+    /// Returns an already received event without blocking. Errors mean the connection to the
+    /// server is broken
+    pub fn poll_event(&mut self) -> Result<Option<Event>, ConnectionError> {
+        let e = match self.pending.pop_front() {
+            Some(e) => e,
+            None => match self.conn.poll_for_event()? {
+                Some(e) => e,
+                None => return Ok(None),
+            },
+        };
 
         match e {
             Event::MotionNotify(e) => {
@@ -177,7 +401,7 @@ impl X11RB {
             _ => {}
         };
 
-        Some(e)
+        Ok(Some(e))
     }
 
     pub fn mouse_pos(&self) -> (i16, i16) {
@@ -248,6 +472,52 @@ impl X11RB {
         Some(())
     }
 
+    /// Sends a `WM_PROTOCOLS` client message (ICCCM 4.2.8), e.g. `WM_DELETE_WINDOW`
+    pub fn send_protocol_message(
+        &mut self,
+        id: WindowId,
+        wm_protocols: Atom,
+        protocol: Atom,
+    ) -> Option<()> {
+        let event = ClientMessageEvent::new(
+            32,
+            id,
+            wm_protocols,
+            [protocol, x11rb::CURRENT_TIME, 0, 0, 0],
+        );
+        self.conn
+            .send_event(false, id, EventMask::NO_EVENT, event)
+            .map_err(|e| {
+                warn!("Failed to send a WM_PROTOCOLS message to window {id}: {e}");
+            })
+            .ok()?;
+        Some(())
+    }
+
+    /// Sends `_NET_WM_PING`, the client answers by sending the same message back to the root window
+    pub fn send_ping(&mut self, id: WindowId, wm_protocols: Atom, ping: Atom) -> Option<()> {
+        let event =
+            ClientMessageEvent::new(32, id, wm_protocols, [ping, x11rb::CURRENT_TIME, id, 0, 0]);
+        self.conn
+            .send_event(false, id, EventMask::NO_EVENT, event)
+            .map_err(|e| {
+                warn!("Failed to ping window {id}: {e}");
+            })
+            .ok()?;
+        Some(())
+    }
+
+    /// Closes the connection of the client owning the window, for programs that are stuck
+    pub fn kill_client(&mut self, id: WindowId) -> Option<()> {
+        self.conn
+            .kill_client(id)
+            .map_err(|e| {
+                warn!("Failed to kill the client of window {id}: {e}");
+            })
+            .ok()?;
+        Some(())
+    }
+
     pub fn move_window(&mut self, id: WindowId, x: i16, y: i16) -> Option<()> {
         self.conn
             .configure_window(id, &ConfigureWindowAux::new().x(x as i32).y(y as i32))
@@ -301,6 +571,7 @@ impl X11RB {
         self.keymap.get(&(c)).copied().unwrap_or(0) as u32
     }
 }
+#[allow(dead_code)]
 pub const XK_BACKSPACE: u32 = 0xff08;
 pub const XK_RETURN: u32 = 0xff0d;
 // pub const XK_RETURN:    u32 = 0x24;
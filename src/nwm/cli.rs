@@ -0,0 +1,200 @@
+use std::path::PathBuf;
+
+pub const USAGE: &str = "\
+Usage: nwm [options]
+
+Options:
+  -c, --config <path>       Config file to load [default: ~/.config/nwm/config.lua]
+  -d, --display <name>      X display to manage [default: $DISPLAY]
+  -l, --log-level <level>   Most verbose level written to the log file: off, error, warn, info, debug
+                            or trace [default: info]
+      --log-file <path>     Where logs are written (read by nwlog) [default: /tmp/nwm.log]
+      --check-config        Load the config, print every problem in it and exit (1 if there were any)
+      --replace             Take over from the window manager that is currently running
+  -h, --help                Print this help
+  -V, --version             Print the version of nwm";
+
+/// What `nwm` was asked to do
+#[derive(Debug, PartialEq)]
+pub enum Cli {
+    Run(Args),
+    CheckConfig(Args),
+    Help,
+    Version,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Args {
+    /// `None` means the default location in the user's config dir
+    pub config: Option<PathBuf>,
+    /// `None` means `$DISPLAY`, see `Args::display_name`. The chosen one is exported as `DISPLAY`
+    /// for the programs nwm starts
+    pub display: Option<String>,
+    pub log_level: log::LevelFilter,
+    pub log_file: PathBuf,
+    pub replace: bool,
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Self {
+            config: None,
+            display: None,
+            log_level: log::LevelFilter::Info,
+            log_file: PathBuf::from("/tmp/nwm.log"),
+            replace: false,
+        }
+    }
+}
+
+impl Args {
+    pub fn display_name(&self) -> Result<String, String> {
+        self.display
+            .clone()
+            .or_else(|| std::env::var("DISPLAY").ok())
+            .filter(|d| !d.is_empty())
+            .ok_or_else(|| {
+                String::from("DISPLAY isn't set, start nwm from an X session (e.g. with startx) or pass --display")
+            })
+    }
+}
+
+/// Parses the arguments without the program name, both `--opt value` and `--opt=value` work
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Cli, String> {
+    let mut parsed = Args::default();
+    let mut check_config = false;
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let (name, inline) = match arg.split_once('=') {
+            Some((name, value)) if name.starts_with("--") => {
+                (name.to_string(), Some(value.to_string()))
+            }
+            _ => (arg, None),
+        };
+        let mut value = || {
+            inline
+                .clone()
+                .or_else(|| args.next())
+                .ok_or_else(|| format!("`{name}` expects a value"))
+        };
+        let flag = || match inline {
+            Some(_) => Err(format!("`{name}` doesn't take a value")),
+            None => Ok(()),
+        };
+
+        match name.as_str() {
+            "-h" | "--help" => return Ok(Cli::Help),
+            "-V" | "--version" => return Ok(Cli::Version),
+            "-c" | "--config" => parsed.config = Some(PathBuf::from(value()?)),
+            "-d" | "--display" => parsed.display = Some(value()?),
+            "-l" | "--log-level" => {
+                let level = value()?;
+                parsed.log_level = level.parse().map_err(|_| {
+                    format!(
+                        "`{level}` isn't a log level, use off, error, warn, info, debug or trace"
+                    )
+                })?;
+            }
+            "--log-file" => parsed.log_file = PathBuf::from(value()?),
+            "--check-config" => {
+                flag()?;
+                check_config = true;
+            }
+            "--replace" => {
+                flag()?;
+                parsed.replace = true;
+            }
+            _ => return Err(format!("unknown option `{name}`")),
+        }
+    }
+
+    Ok(if check_config {
+        Cli::CheckConfig(parsed)
+    } else {
+        Cli::Run(parsed)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_line(line: &str) -> Result<Cli, String> {
+        parse(line.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn parses_valid_arguments() {
+        let cases = [
+            ("", Cli::Run(Args::default())),
+            ("-h", Cli::Help),
+            ("--config a.lua --help", Cli::Help),
+            ("-V", Cli::Version),
+            ("--version", Cli::Version),
+            (
+                "-c a.lua",
+                Cli::Run(Args {
+                    config: Some(PathBuf::from("a.lua")),
+                    ..Default::default()
+                }),
+            ),
+            (
+                "--config=a.lua --display :1",
+                Cli::Run(Args {
+                    config: Some(PathBuf::from("a.lua")),
+                    display: Some(String::from(":1")),
+                    ..Default::default()
+                }),
+            ),
+            (
+                "-l debug --log-file=/tmp/x.log --replace",
+                Cli::Run(Args {
+                    log_level: log::LevelFilter::Debug,
+                    log_file: PathBuf::from("/tmp/x.log"),
+                    replace: true,
+                    ..Default::default()
+                }),
+            ),
+            (
+                "--log-level=off -d :2",
+                Cli::Run(Args {
+                    display: Some(String::from(":2")),
+                    log_level: log::LevelFilter::Off,
+                    ..Default::default()
+                }),
+            ),
+            ("--check-config", Cli::CheckConfig(Args::default())),
+            (
+                "--replace --check-config",
+                Cli::CheckConfig(Args {
+                    replace: true,
+                    ..Default::default()
+                }),
+            ),
+        ];
+        for (line, cli) in cases {
+            assert_eq!(parse_line(line), Ok(cli), "{line:?}");
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_arguments() {
+        let cases = [
+            ("--config", "`--config` expects a value"),
+            ("-d", "`-d` expects a value"),
+            (
+                "-l loud",
+                "`loud` isn't a log level, use off, error, warn, info, debug or trace",
+            ),
+            ("--replace=yes", "`--replace` doesn't take a value"),
+            ("--check-config=1", "`--check-config` doesn't take a value"),
+            ("--nope", "unknown option `--nope`"),
+            ("-c=a.lua", "unknown option `-c=a.lua`"),
+            ("config.lua", "unknown option `config.lua`"),
+        ];
+        for (line, error) in cases {
+            assert_eq!(parse_line(line), Err(String::from(error)), "{line:?}");
+        }
+    }
+}
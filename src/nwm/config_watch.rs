@@ -0,0 +1,149 @@
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    os::fd::{AsFd, BorrowedFd},
+    path::{Path, PathBuf},
+};
+
+use log::{info, warn};
+use nix::{
+    errno::Errno,
+    sys::inotify::{AddWatchFlags, InitFlags, Inotify, WatchDescriptor},
+};
+
+use crate::lua_cfg;
+
+/// Watches the directory of config.lua and the directories modules are required from (along with
+/// every directory below them, `require("a.b.c")` loads `a/b/c.lua`) for changes to lua files
+pub struct ConfigWatch {
+    inotify: Inotify,
+    /// Every watched directory, directories created inside of them get watched too
+    dirs: HashMap<WatchDescriptor, PathBuf>,
+}
+
+/// Editors often write a new file and rename it over the old one
+const WATCH_FLAGS: AddWatchFlags = AddWatchFlags::IN_CLOSE_WRITE
+    .union(AddWatchFlags::IN_MOVED_TO)
+    .union(AddWatchFlags::IN_MOVED_FROM)
+    .union(AddWatchFlags::IN_DELETE)
+    .union(AddWatchFlags::IN_CREATE);
+
+impl ConfigWatch {
+    pub fn new(config_path: &Path) -> Option<Self> {
+        let dir = config_path.parent().filter(|d| !d.as_os_str().is_empty());
+        let dir = dir.unwrap_or(Path::new(".")).to_path_buf();
+        let mut dirs = vec![dir];
+        for d in lua_cfg::module_dirs(config_path) {
+            if !dirs.contains(&d) {
+                dirs.push(d);
+            }
+        }
+
+        let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)
+            .map_err(|e| {
+                warn!("Failed to set up inotify, the config won't be reloaded on changes: {e}")
+            })
+            .ok()?;
+
+        let mut watch = Self {
+            inotify,
+            dirs: HashMap::new(),
+        };
+        for dir in &dirs {
+            watch.watch_tree(dir);
+            info!("Watching {} for config changes", dir.display());
+        }
+        Some(watch)
+    }
+
+    pub fn fd(&self) -> BorrowedFd<'_> {
+        self.inotify.as_fd()
+    }
+
+    /// Reads every pending event, true if any of them was about a lua file. Starts watching the
+    /// directories that were created in (or moved into) the watched ones
+    pub fn changed(&mut self) -> bool {
+        let mut changed = false;
+        loop {
+            match self.inotify.read_events() {
+                Ok(events) => {
+                    for e in events {
+                        // The kernel dropped the watch, the directory is gone
+                        if e.mask.contains(AddWatchFlags::IN_IGNORED) {
+                            self.dirs.remove(&e.wd);
+                            continue;
+                        }
+                        let (Some(name), Some(parent)) = (&e.name, self.dirs.get(&e.wd)) else {
+                            continue;
+                        };
+                        let path = parent.join(name);
+
+                        let is_dir = e.mask.contains(AddWatchFlags::IN_ISDIR);
+                        if is_dir
+                            && e.mask
+                                .intersects(AddWatchFlags::IN_CREATE | AddWatchFlags::IN_MOVED_TO)
+                            && !is_hidden(name)
+                        {
+                            self.watch_tree(&path);
+                        } else if is_dir && e.mask.contains(AddWatchFlags::IN_MOVED_FROM) {
+                            // The watches stay on the moved directories, with paths that are
+                            // wrong now. Moving them back in watches them again
+                            self.unwatch_tree(&path);
+                        }
+                        // A created file is only worth a reload once it's written
+                        changed |= !is_dir
+                            && !e.mask.contains(AddWatchFlags::IN_CREATE)
+                            && Path::new(name).extension().is_some_and(|e| e == "lua");
+                    }
+                }
+                Err(Errno::EAGAIN) => return changed,
+                Err(Errno::EINTR) => {}
+                Err(e) => {
+                    warn!("Failed to read inotify events: {e}");
+                    return changed;
+                }
+            }
+        }
+    }
+
+    /// Watches `dir` and every directory below it. Symlinks aren't followed (they could loop) and
+    /// hidden directories (`.git`, ...) are skipped, `require` doesn't look in either
+    fn watch_tree(&mut self, dir: &Path) {
+        match self.inotify.add_watch(dir, WATCH_FLAGS) {
+            Ok(wd) => _ = self.dirs.insert(wd, dir.to_path_buf()),
+            Err(e) => {
+                warn!("Failed to watch {} for config changes: {e}", dir.display());
+                return;
+            }
+        }
+
+        let subdirs = std::fs::read_dir(dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter(|e| e.file_type().is_ok_and(|t| t.is_dir()))
+            .filter(|e| !is_hidden(&e.file_name()))
+            .map(|e| e.path())
+            .collect::<Vec<_>>();
+        for d in subdirs {
+            self.watch_tree(&d);
+        }
+    }
+
+    fn unwatch_tree(&mut self, dir: &Path) {
+        let wds = self
+            .dirs
+            .iter()
+            .filter(|(_, d)| d.starts_with(dir))
+            .map(|(wd, _)| *wd)
+            .collect::<Vec<_>>();
+        for wd in wds {
+            _ = self.inotify.rm_watch(wd);
+            self.dirs.remove(&wd);
+        }
+    }
+}
+
+fn is_hidden(name: &OsStr) -> bool {
+    name.as_encoded_bytes().starts_with(b".")
+}
@@ -0,0 +1,397 @@
+use std::{
+    io::{ErrorKind, Read, Write},
+    os::{
+        fd::{AsFd, BorrowedFd},
+        unix::net::{UnixListener, UnixStream},
+    },
+    path::PathBuf,
+};
+
+use log::{info, warn};
+
+use crate::lua_cfg::Action;
+
+/// `$NWM_SOCKET` or `$XDG_RUNTIME_DIR/nwm-$DISPLAY.sock` (`/tmp` when there is no runtime dir)
+pub fn socket_path(display: &str) -> PathBuf {
+    if let Ok(p) = std::env::var("NWM_SOCKET") {
+        return PathBuf::from(p);
+    }
+    let dir = std::env::var("XDG_RUNTIME_DIR").unwrap_or_else(|_| String::from("/tmp"));
+    PathBuf::from(dir).join(format!("nwm-{display}.sock"))
+}
+
+/// Something a client asked nwm to do, one per line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Action(Action),
+    SwitchWs(usize),
+    MoveToWs(usize),
+    GetWorkspaces,
+    /// Everything nwm knows: workspaces, windows, struts, screen size and settings
+    GetTree,
+    /// Turns the connection into a stream of events, every kind if the list is empty
+    Subscribe(Vec<EventKind>),
+}
+
+/// What subscribers get notified about, sent as `{"event": "<name>", ...}`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    Workspace,
+    Focus,
+    WindowOpen,
+    WindowClose,
+    Title,
+    Layout,
+    Reload,
+}
+
+impl EventKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Workspace => "workspace",
+            Self::Focus => "focus",
+            Self::WindowOpen => "window_open",
+            Self::WindowClose => "window_close",
+            Self::Title => "title",
+            Self::Layout => "layout",
+            Self::Reload => "reload",
+        }
+    }
+}
+
+impl std::str::FromStr for EventKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "workspace" => Ok(Self::Workspace),
+            "focus" => Ok(Self::Focus),
+            "window_open" => Ok(Self::WindowOpen),
+            "window_close" => Ok(Self::WindowClose),
+            "title" => Ok(Self::Title),
+            "layout" => Ok(Self::Layout),
+            "reload" => Ok(Self::Reload),
+            _ => Err(format!("unknown event `{s}`")),
+        }
+    }
+}
+
+impl std::str::FromStr for Command {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let Some(name) = words.next() else {
+            return Err(String::from("empty command"));
+        };
+        let args = words.collect::<Vec<_>>();
+
+        let workspace = || -> Result<usize, String> {
+            match args.as_slice() {
+                [n] => match n.parse::<usize>() {
+                    Ok(n) if n < 10 => Ok(n),
+                    _ => Err(format!("`{n}` is not a workspace (0-9)")),
+                },
+                _ => Err(format!("`{name}` expects exactly one workspace index")),
+            }
+        };
+
+        let action = match name {
+            "ws" => return workspace().map(Self::SwitchWs),
+            "move-to" => return workspace().map(Self::MoveToWs),
+            "get_workspaces" if args.is_empty() => return Ok(Self::GetWorkspaces),
+            "get_tree" if args.is_empty() => return Ok(Self::GetTree),
+            "subscribe" => {
+                return args
+                    .iter()
+                    .map(|a| a.parse())
+                    .collect::<Result<_, _>>()
+                    .map(Self::Subscribe);
+            }
+            "focus-left" => Action::FocusLeft,
+            "focus-right" => Action::FocusRight,
+            "move-left" => Action::MoveLeft,
+            "move-right" => Action::MoveRight,
+            "launcher" => Action::Launcher,
+            "terminal" => Action::Terminal,
+            "close" => Action::CloseWindow,
+            "kill" => Action::KillWindow,
+            "fullscreen" => Action::ToggleFullscreen,
+            "next-ws" => Action::NextWs,
+            "prev-ws" => Action::PrevWs,
+            "next-layout" => Action::NextLayout,
+            "prev-layout" => Action::PrevLayout,
+            "reload" => Action::ReloadConfig,
+            "restart" => Action::Restart,
+            "quit" => Action::Quit,
+            _ => return Err(format!("unknown command `{name}`")),
+        };
+
+        if !args.is_empty() {
+            return Err(format!("`{name}` doesn't take any arguments"));
+        }
+        Ok(Self::Action(action))
+    }
+}
+
+pub type ClientId = u64;
+
+struct Client {
+    id: ClientId,
+    stream: UnixStream,
+    buf: Vec<u8>,
+    /// The client won't send anything anymore, it still gets the replies to what it already sent
+    /// and is dropped on the next read
+    closed: bool,
+    /// `Some` once the client subscribed, empty means every event
+    subscription: Option<Vec<EventKind>>,
+}
+
+impl Client {
+    fn wants(&self, kind: EventKind) -> bool {
+        self.subscription
+            .as_ref()
+            .is_some_and(|s| s.is_empty() || s.contains(&kind))
+    }
+}
+
+/// Non blocking unix socket server, polled together with the x11 connection in `Nwm::run`
+pub struct IpcServer {
+    listener: UnixListener,
+    path: PathBuf,
+    clients: Vec<Client>,
+    next_id: ClientId,
+}
+
+impl IpcServer {
+    pub fn bind(path: PathBuf) -> Option<Self> {
+        let listener = match UnixListener::bind(&path) {
+            Ok(l) => l,
+            Err(e) if e.kind() == ErrorKind::AddrInUse => {
+                if UnixStream::connect(&path).is_ok() {
+                    warn!(
+                        "Another nwm is already listening on {}, ipc is disabled",
+                        path.display()
+                    );
+                    return None;
+                }
+                // Left behind by an nwm that didn't exit cleanly
+                _ = std::fs::remove_file(&path);
+                UnixListener::bind(&path)
+                    .map_err(|e| warn!("Failed to bind ipc socket {}: {e}", path.display()))
+                    .ok()?
+            }
+            Err(e) => {
+                warn!("Failed to bind ipc socket {}: {e}", path.display());
+                return None;
+            }
+        };
+        listener
+            .set_nonblocking(true)
+            .map_err(|e| warn!("Failed to make the ipc socket non blocking: {e}"))
+            .ok()?;
+
+        info!("Listening for ipc commands on {}", path.display());
+        Some(Self {
+            listener,
+            path,
+            clients: Vec::new(),
+            next_id: 0,
+        })
+    }
+
+    pub fn fds(&self) -> Vec<BorrowedFd<'_>> {
+        std::iter::once(self.listener.as_fd())
+            .chain(
+                self.clients
+                    .iter()
+                    .filter(|c| !c.closed)
+                    .map(|c| c.stream.as_fd()),
+            )
+            .collect()
+    }
+
+    /// Accepts new clients and returns every complete line that was sent since the last call
+    pub fn read_requests(&mut self) -> Vec<(ClientId, Result<Command, String>)> {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(e) = stream.set_nonblocking(true) {
+                        warn!("Failed to make ipc client non blocking: {e}");
+                        continue;
+                    }
+                    self.clients.push(Client {
+                        id: self.next_id,
+                        stream,
+                        buf: Vec::new(),
+                        closed: false,
+                        subscription: None,
+                    });
+                    self.next_id += 1;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    warn!("Failed to accept ipc client: {e}");
+                    break;
+                }
+            }
+        }
+
+        self.clients.retain(|c| !c.closed);
+
+        let mut requests = Vec::new();
+        for c in &mut self.clients {
+            let mut chunk = [0; 1024];
+            c.closed = loop {
+                match c.stream.read(&mut chunk) {
+                    Ok(0) => break true,
+                    Ok(n) => c.buf.extend_from_slice(&chunk[..n]),
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => break false,
+                    Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                    Err(e) => {
+                        warn!("Failed to read from ipc client: {e}");
+                        break true;
+                    }
+                }
+            };
+
+            while let Some(end) = c.buf.iter().position(|b| *b == b'\n') {
+                let line = c.buf.drain(..=end).collect::<Vec<_>>();
+                let line = String::from_utf8_lossy(&line);
+                let line = line.trim();
+                if !line.is_empty() {
+                    requests.push((c.id, line.parse()));
+                }
+            }
+        }
+        requests
+    }
+
+    /// Sends one line of json to a client, clients that can't be written to get dropped
+    pub fn reply(&mut self, id: ClientId, reply: &serde_json::Value) {
+        let Some(p) = self.clients.iter().position(|c| c.id == id) else {
+            return;
+        };
+        let mut line = reply.to_string();
+        line.push('\n');
+        if let Err(e) = self.clients[p].stream.write_all(line.as_bytes()) {
+            warn!("Failed to reply to ipc client: {e}");
+            self.clients.remove(p);
+        }
+    }
+}
+
+impl IpcServer {
+    pub fn subscribe(&mut self, id: ClientId, kinds: Vec<EventKind>) {
+        if let Some(c) = self.clients.iter_mut().find(|c| c.id == id) {
+            c.subscription = Some(kinds);
+        }
+    }
+
+    pub fn has_subscribers(&self, kind: EventKind) -> bool {
+        self.clients.iter().any(|c| c.wants(kind))
+    }
+
+    /// Sends `{"event": kind, ...data}` to everyone subscribed to `kind`. Subscribers which can't
+    /// keep up (their socket buffer is full) get dropped
+    pub fn broadcast(&mut self, kind: EventKind, data: serde_json::Value) {
+        let mut event = serde_json::json!({ "event": kind.name() });
+        if let (Some(event), serde_json::Value::Object(data)) = (event.as_object_mut(), data) {
+            event.extend(data);
+        }
+        let mut line = event.to_string();
+        line.push('\n');
+
+        self.clients.retain_mut(|c| {
+            if !c.wants(kind) {
+                return true;
+            }
+            match c.stream.write_all(line.as_bytes()) {
+                Ok(()) => true,
+                Err(e) => {
+                    info!("Dropping ipc subscriber: {e}");
+                    false
+                }
+            }
+        });
+    }
+}
+
+impl Drop for IpcServer {
+    fn drop(&mut self) {
+        _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_valid_commands() {
+        let cases = [
+            ("ws 3", Command::SwitchWs(3)),
+            ("  move-to 0 ", Command::MoveToWs(0)),
+            ("ws 9", Command::SwitchWs(9)),
+            ("get_workspaces", Command::GetWorkspaces),
+            ("get_tree", Command::GetTree),
+            ("subscribe", Command::Subscribe(vec![])),
+            (
+                "subscribe focus window_open",
+                Command::Subscribe(vec![EventKind::Focus, EventKind::WindowOpen]),
+            ),
+            ("close", Command::Action(Action::CloseWindow)),
+            ("kill", Command::Action(Action::KillWindow)),
+            ("fullscreen", Command::Action(Action::ToggleFullscreen)),
+            ("next-layout", Command::Action(Action::NextLayout)),
+            ("restart", Command::Action(Action::Restart)),
+            ("quit", Command::Action(Action::Quit)),
+        ];
+        for (line, command) in cases {
+            assert_eq!(line.parse::<Command>(), Ok(command), "{line:?}");
+        }
+    }
+
+    #[test]
+    fn rejects_out_of_range_workspaces() {
+        for line in ["ws 10", "move-to 10", "ws -1", "ws 99999999999999999999"] {
+            assert!(line.parse::<Command>().is_err(), "{line:?}");
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_commands() {
+        let cases = [
+            "",
+            "   ",
+            "ws",
+            "ws one",
+            "ws 1 2",
+            "move-to",
+            "close now",
+            "get_tree please",
+            "subscribe keypress",
+            "dance",
+            "WS 1",
+        ];
+        for line in cases {
+            assert!(line.parse::<Command>().is_err(), "{line:?}");
+        }
+    }
+
+    #[test]
+    fn errors_name_the_problem() {
+        assert_eq!(
+            "ws 10".parse::<Command>(),
+            Err(String::from("`10` is not a workspace (0-9)"))
+        );
+        assert_eq!(
+            "dance".parse::<Command>(),
+            Err(String::from("unknown command `dance`"))
+        );
+        assert_eq!(
+            "subscribe keypress".parse::<Command>(),
+            Err(String::from("unknown event `keypress`"))
+        );
+    }
+}
@@ -0,0 +1,452 @@
+use log::warn;
+use mlua::Lua;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Rect {
+    pub x: i16,
+    pub y: i16,
+    pub w: i16,
+    pub h: i16,
+}
+
+/// Everything (besides the window count and usable area) a layout needs to arrange windows
+#[derive(Debug, Clone, Copy)]
+pub struct LayoutParams {
+    pub gap: i16,
+    pub master_ratio: f32,
+    pub master_count: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Layout {
+    /// Every window gets an equally wide column
+    #[default]
+    Horizontal,
+    /// Every window gets an equally tall row
+    Vertical,
+    /// `master_count` windows on the left taking `master_ratio` of the width, the rest stacked on
+    /// the right
+    MasterStack,
+    /// Windows are put into a (roughly) square grid, filled row by row
+    Grid,
+    /// Every window takes up the whole usable area, only the focused one is visible
+    Monocle,
+    /// A layout registered from config.lua with `nwm.layout.register`
+    Custom(String),
+}
+
+/// A lua function registered with `nwm.layout.register(name, function(n, area, gap) ... end)`
+#[derive(Debug, Clone)]
+pub struct CustomLayout {
+    pub name: String,
+    pub function: mlua::Function,
+}
+
+impl CustomLayout {
+    /// Calls the lua function and checks that it returned exactly `n` rects with a positive size
+    /// which lie inside of `area`
+    fn arrange(&self, n: usize, area: Rect, gap: i16) -> Result<Vec<Rect>, String> {
+        let lua_area = [area.x, area.y, area.w, area.h];
+        let rects = self
+            .function
+            .call::<Vec<[i32; 4]>>((n, lua_area, gap))
+            .map_err(|e| e.to_string())?;
+
+        if rects.len() != n {
+            return Err(format!("returned {} rects for {n} windows", rects.len()));
+        }
+
+        rects
+            .into_iter()
+            .map(|[x, y, w, h]| {
+                let inside = x >= area.x as i32
+                    && y >= area.y as i32
+                    && w > 0
+                    && h > 0
+                    && x as i64 + w as i64 <= (area.x + area.w) as i64
+                    && y as i64 + h as i64 <= (area.y + area.h) as i64;
+                if !inside {
+                    return Err(format!(
+                        "rect {{{x}, {y}, {w}, {h}}} is empty or outside of the usable area {{{}, {}, {}, {}}}",
+                        area.x, area.y, area.w, area.h
+                    ));
+                }
+                Ok(Rect {
+                    x: x as i16,
+                    y: y as i16,
+                    w: w as i16,
+                    h: h as i16,
+                })
+            })
+            .collect()
+    }
+}
+
+impl Layout {
+    /// The order in which `NextLayout` and `PrevLayout` cycle through the layouts
+    pub const ALL: [Layout; 5] = [
+        Layout::Horizontal,
+        Layout::Vertical,
+        Layout::MasterStack,
+        Layout::Grid,
+        Layout::Monocle,
+    ];
+
+    /// The built-in layouts followed by the registered custom ones
+    fn cycle(custom: &[CustomLayout]) -> Vec<Layout> {
+        Self::ALL
+            .into_iter()
+            .chain(custom.iter().map(|c| Layout::Custom(c.name.clone())))
+            .collect()
+    }
+
+    /// The layout displayed as `name`, if it exists
+    pub fn from_name(name: &str, custom: &[CustomLayout]) -> Option<Self> {
+        Self::cycle(custom)
+            .into_iter()
+            .find(|l| l.to_string() == name)
+    }
+
+    pub fn next(&self, custom: &[CustomLayout]) -> Self {
+        let all = Self::cycle(custom);
+        let p = all.iter().position(|l| l == self).unwrap_or(0);
+        all[(p + 1) % all.len()].clone()
+    }
+
+    pub fn prev(&self, custom: &[CustomLayout]) -> Self {
+        let all = Self::cycle(custom);
+        let p = all.iter().position(|l| l == self).unwrap_or(0);
+        all[(p + all.len() - 1) % all.len()].clone()
+    }
+
+    /// Computes the rectangles for `n` tiled windows inside of `area` (the screen without the
+    /// space reserved by docks). The returned vector always has `n` elements, in the same order as
+    /// the windows of the workspace. Custom layouts that fail or return bogus rects fall back to
+    /// `Horizontal`
+    pub fn arrange(
+        &self,
+        n: usize,
+        area: Rect,
+        params: &LayoutParams,
+        custom: &[CustomLayout],
+    ) -> Vec<Rect> {
+        if n == 0 {
+            return vec![];
+        }
+        match self {
+            Self::Horizontal => horizontal(n, area, params.gap),
+            Self::Vertical => column(inset(area, params.gap), n, params.gap),
+            Self::MasterStack => master_stack(n, area, params),
+            Self::Grid => grid(n, area, params.gap),
+            Self::Monocle => vec![inset(area, params.gap); n],
+            Self::Custom(name) => {
+                let Some(c) = custom.iter().find(|c| &c.name == name) else {
+                    warn!("Layout `{name}` was never registered, using the horizontal layout");
+                    return horizontal(n, area, params.gap);
+                };
+                c.arrange(n, area, params.gap).unwrap_or_else(|e| {
+                    warn!("Custom layout `{name}` failed: {e}, using the horizontal layout");
+                    horizontal(n, area, params.gap)
+                })
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Layout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Horizontal => write!(f, "horizontal"),
+            Self::Vertical => write!(f, "vertical"),
+            Self::MasterStack => write!(f, "master_stack"),
+            Self::Grid => write!(f, "grid"),
+            Self::Monocle => write!(f, "monocle"),
+            Self::Custom(name) => write!(f, "{name}"),
+        }
+    }
+}
+
+fn horizontal(n: usize, area: Rect, gap: i16) -> Vec<Rect> {
+    let n = n as i16;
+    let half_gap = gap / 2;
+
+    let usable_w = area.w - gap * 2;
+    let slot_w = usable_w / n;
+
+    (0..n)
+        .map(|i| Rect {
+            x: area.x + gap + i * slot_w + half_gap,
+            y: area.y + gap,
+            w: slot_w - half_gap * 2,
+            h: area.h - gap * 2,
+        })
+        .collect()
+}
+
+fn master_stack(n: usize, area: Rect, params: &LayoutParams) -> Vec<Rect> {
+    let gap = params.gap;
+    let half_gap = gap / 2;
+
+    let inner = inset(area, gap);
+
+    let masters = params.master_count.min(n);
+    let stacked = n - masters;
+
+    if masters == 0 || stacked == 0 {
+        return column(inner, n, gap);
+    }
+
+    let master_w = (inner.w as f32 * params.master_ratio) as i16;
+
+    let mut rs = column(
+        Rect {
+            w: master_w - half_gap,
+            ..inner
+        },
+        masters,
+        gap,
+    );
+    rs.extend(column(
+        Rect {
+            x: inner.x + master_w + half_gap,
+            w: inner.w - master_w - half_gap,
+            ..inner
+        },
+        stacked,
+        gap,
+    ));
+    rs
+}
+
+fn grid(n: usize, area: Rect, gap: i16) -> Vec<Rect> {
+    let inner = inset(area, gap);
+
+    let cols = (n as f32).sqrt().ceil() as usize;
+    let rows = n.div_ceil(cols);
+    let row_h = (inner.h - gap * (rows as i16 - 1)) / rows as i16;
+
+    (0..rows)
+        .flat_map(|r| {
+            // The last row might not be full, its windows get stretched to fill the width
+            let in_row = cols.min(n - r * cols);
+            row(
+                Rect {
+                    y: inner.y + r as i16 * (row_h + gap),
+                    h: row_h,
+                    ..inner
+                },
+                in_row,
+                gap,
+            )
+        })
+        .collect()
+}
+
+/// Shrinks `area` by `gap` on every side
+fn inset(area: Rect, gap: i16) -> Rect {
+    Rect {
+        x: area.x + gap,
+        y: area.y + gap,
+        w: area.w - gap * 2,
+        h: area.h - gap * 2,
+    }
+}
+
+/// Splits `area` into `n` columns of equal width separated by `gap`
+fn row(area: Rect, n: usize, gap: i16) -> Vec<Rect> {
+    let n = n as i16;
+    let slot_w = (area.w - gap * (n - 1)) / n;
+
+    (0..n)
+        .map(|i| Rect {
+            x: area.x + i * (slot_w + gap),
+            y: area.y,
+            w: slot_w,
+            h: area.h,
+        })
+        .collect()
+}
+
+/// Splits `area` into `n` rows of equal height separated by `gap`
+fn column(area: Rect, n: usize, gap: i16) -> Vec<Rect> {
+    let n = n as i16;
+    let slot_h = (area.h - gap * (n - 1)) / n;
+
+    (0..n)
+        .map(|i| Rect {
+            x: area.x,
+            y: area.y + i * (slot_h + gap),
+            w: area.w,
+            h: slot_h,
+        })
+        .collect()
+}
+
+impl mlua::UserData for Layout {}
+impl mlua::FromLua for Layout {
+    fn from_lua(value: mlua::Value, _lua: &Lua) -> mlua::Result<Self> {
+        match value {
+            mlua::Value::UserData(ud) => Ok(ud.borrow::<Layout>()?.clone()),
+            _ => Err(mlua::Error::FromLuaConversionError {
+                from: "Lua side layout constant",
+                to: "Rust side layout constant".to_string(),
+                message: Some(
+                    "You might have specified a non-layout value in config.lua, use one of nwm.layout.*"
+                        .to_string(),
+                ),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCREEN: Rect = Rect {
+        x: 0,
+        y: 0,
+        w: 1000,
+        h: 500,
+    };
+
+    fn params(gap: i16, master_count: usize) -> LayoutParams {
+        LayoutParams {
+            gap,
+            master_ratio: 0.5,
+            master_count,
+        }
+    }
+
+    fn rect(x: i16, y: i16, w: i16, h: i16) -> Rect {
+        Rect { x, y, w, h }
+    }
+
+    fn overlap(a: &Rect, b: &Rect) -> bool {
+        a.x < b.x + b.w && b.x < a.x + a.w && a.y < b.y + b.h && b.y < a.y + a.h
+    }
+
+    #[test]
+    fn no_windows_no_rects() {
+        for l in Layout::ALL {
+            assert!(l.arrange(0, SCREEN, &params(10, 1), &[]).is_empty(), "{l}");
+        }
+    }
+
+    #[test]
+    fn single_window_fills_the_area_minus_gaps() {
+        let p = params(10, 1);
+        assert_eq!(
+            Layout::Horizontal.arrange(1, SCREEN, &p, &[]),
+            [rect(15, 10, 970, 480)]
+        );
+        for l in [
+            Layout::Vertical,
+            Layout::MasterStack,
+            Layout::Grid,
+            Layout::Monocle,
+        ] {
+            assert_eq!(
+                l.arrange(1, SCREEN, &p, &[]),
+                [rect(10, 10, 980, 480)],
+                "{l}"
+            );
+        }
+    }
+
+    #[test]
+    fn master_stack_two_windows() {
+        assert_eq!(
+            Layout::MasterStack.arrange(2, SCREEN, &params(0, 1), &[]),
+            [rect(0, 0, 500, 500), rect(500, 0, 500, 500)]
+        );
+    }
+
+    #[test]
+    fn master_stack_odd_count_stacks_the_rest() {
+        assert_eq!(
+            Layout::MasterStack.arrange(3, SCREEN, &params(10, 1), &[]),
+            [
+                rect(10, 10, 485, 480),
+                rect(505, 10, 485, 235),
+                rect(505, 255, 485, 235),
+            ]
+        );
+    }
+
+    #[test]
+    fn master_count_above_window_count_is_a_single_column() {
+        assert_eq!(
+            Layout::MasterStack.arrange(2, SCREEN, &params(0, 5), &[]),
+            [rect(0, 0, 1000, 250), rect(0, 250, 1000, 250)]
+        );
+    }
+
+    #[test]
+    fn master_count_zero_is_a_single_column() {
+        assert_eq!(
+            Layout::MasterStack.arrange(2, SCREEN, &params(0, 0), &[]),
+            [rect(0, 0, 1000, 250), rect(0, 250, 1000, 250)]
+        );
+    }
+
+    #[test]
+    fn grid_stretches_the_last_row() {
+        assert_eq!(
+            Layout::Grid.arrange(3, SCREEN, &params(0, 1), &[]),
+            [
+                rect(0, 0, 500, 250),
+                rect(500, 0, 500, 250),
+                rect(0, 250, 1000, 250),
+            ]
+        );
+        let rs = Layout::Grid.arrange(5, SCREEN, &params(0, 1), &[]);
+        assert_eq!(rs.len(), 5);
+        assert_eq!(rs[3], rect(0, 250, 500, 250));
+        assert_eq!(rs[4], rect(500, 250, 500, 250));
+    }
+
+    #[test]
+    fn monocle_stacks_every_window_on_the_same_rect() {
+        let rs = Layout::Monocle.arrange(3, SCREEN, &params(10, 1), &[]);
+        assert_eq!(rs, [rect(10, 10, 980, 480); 3]);
+    }
+
+    #[test]
+    fn strut_offsets_move_every_rect_into_the_area() {
+        // 30px dock on the left and 20px on top
+        let area = rect(30, 20, 970, 480);
+        assert_eq!(
+            Layout::Horizontal.arrange(1, area, &params(0, 1), &[]),
+            [area]
+        );
+        assert_eq!(
+            Layout::MasterStack.arrange(2, area, &params(10, 1), &[]),
+            [rect(40, 30, 470, 460), rect(520, 30, 470, 460)]
+        );
+    }
+
+    #[test]
+    fn tiled_rects_stay_inside_the_area_without_overlapping() {
+        let area = rect(30, 20, 970, 480);
+        for l in Layout::ALL {
+            for n in 1..=9 {
+                for gap in [0, 8] {
+                    let rs = l.arrange(n, area, &params(gap, 2), &[]);
+                    assert_eq!(rs.len(), n, "{l} n={n}");
+                    for (i, r) in rs.iter().enumerate() {
+                        assert!(r.w > 0 && r.h > 0, "{l} n={n}: {r:?}");
+                        assert!(r.x >= area.x && r.y >= area.y, "{l} n={n}: {r:?}");
+                        assert!(r.x + r.w <= area.x + area.w, "{l} n={n}: {r:?}");
+                        assert!(r.y + r.h <= area.y + area.h, "{l} n={n}: {r:?}");
+                        if l != Layout::Monocle {
+                            for o in &rs[i + 1..] {
+                                assert!(!overlap(r, o), "{l} n={n}: {r:?} {o:?}");
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
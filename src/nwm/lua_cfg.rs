@@ -1,21 +1,332 @@
 use log::error;
-use std::sync::{Arc, Mutex};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
 
-use mlua::Lua;
+use mlua::{FromLua, Lua};
 
-pub fn load_config(path: &std::path::Path, reload: bool) -> Result<Config, ()> {
+use crate::layout::{CustomLayout, Layout};
+
+/// Something wrong with the config, pointing at the line responsible for it when that is known
+#[derive(Debug, Clone)]
+pub struct ConfigError {
+    pub file: PathBuf,
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{}:{line}: {}", self.file.display(), self.message),
+            None => write!(f, "{}: {}", self.file.display(), self.message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl ConfigError {
+    fn new(file: &Path, message: impl Into<String>) -> Self {
+        Self {
+            file: file.to_path_buf(),
+            line: None,
+            message: message.into(),
+        }
+    }
+
+    /// Points at the line of lua code that called the running rust function
+    fn at_caller(lua: &Lua, message: String) -> Self {
+        let (file, line) = lua
+            .inspect_stack(1, |d| {
+                let file = d
+                    .source()
+                    .source
+                    .map(|s| s.trim_start_matches('@').to_string());
+                (file, d.current_line())
+            })
+            .unwrap_or_default();
+        Self {
+            file: PathBuf::from(file.unwrap_or_else(|| String::from("config.lua"))),
+            line,
+            message,
+        }
+    }
+
+    /// Digs the location out of an error that happened while running `file`. Errors raised by
+    /// `nwm.set.*` already carry it, lua errors are prefixed with `file:line:`, errors from other
+    /// rust functions only have it in their traceback
+    fn from_lua(e: &mlua::Error, file: &Path) -> Self {
+        match e {
+            mlua::Error::CallbackError { traceback, cause } => {
+                let mut error = Self::from_lua(cause, file);
+                if error.line.is_none()
+                    && let Some((f, line, _)) = traceback
+                        .lines()
+                        .map(str::trim)
+                        .filter(|l| !l.starts_with("[C]"))
+                        .find_map(split_location)
+                {
+                    error.file = PathBuf::from(f);
+                    error.line = Some(line);
+                }
+                error
+            }
+            mlua::Error::ExternalError(ext) => match ext.downcast_ref::<ConfigError>() {
+                Some(e) => e.clone(),
+                None => Self::new(file, ext.to_string()),
+            },
+            mlua::Error::SyntaxError { message, .. } | mlua::Error::RuntimeError(message) => {
+                // The traceback is already pointed at by the location
+                let message = message
+                    .split_once("\nstack traceback:")
+                    .map_or(message.as_str(), |(m, _)| m);
+                // `require` wraps syntax errors of modules in "error loading module 'm' from file
+                // 'path':\n\t" in front of the actual error
+                let message = message
+                    .strip_prefix("error loading module")
+                    .and_then(|m| m.split_once(":\n\t"))
+                    .map_or(message, |(_, m)| m);
+                match split_location(message) {
+                    Some((f, line, message)) => Self {
+                        file: PathBuf::from(f),
+                        line: Some(line),
+                        message: message.to_string(),
+                    },
+                    None => Self::new(file, message),
+                }
+            }
+            e => Self::new(file, e.to_string()),
+        }
+    }
+}
+
+/// Splits `file:line: message` (the format of lua errors and tracebacks)
+fn split_location(s: &str) -> Option<(&str, usize, &str)> {
+    s.match_indices(':').find_map(|(i, _)| {
+        let (line, rest) = s[i + 1..].split_once(':')?;
+        Some((&s[..i], line.parse().ok()?, rest.trim()))
+    })
+}
+
+/// Reports a bad value given to `nwm.set.*`, `nwm.bind` or `nwm.rule`. Raises a lua error, or when only checking the config
+/// writes it down and carries on so every problem gets reported
+fn reject(lua: &Lua, config: &RefCell<Config>, message: String) -> mlua::Result<()> {
+    let error = ConfigError::at_caller(lua, message);
+    match &mut config.borrow_mut().problems {
+        Some(problems) => {
+            problems.push(error);
+            Ok(())
+        }
+        None => Err(mlua::Error::external(error)),
+    }
+}
+
+/// Converts an argument given to a config function, a value of the wrong type is rejected like a
+/// bad one (`what` says what was expected). `None` when it was rejected while checking the config
+fn arg<T: FromLua>(
+    lua: &Lua,
+    config: &RefCell<Config>,
+    value: mlua::Value,
+    what: &str,
+) -> mlua::Result<Option<T>> {
+    match T::from_lua(value.clone(), lua) {
+        Ok(v) => Ok(Some(v)),
+        Err(_) => reject(lua, config, format!("{what}, got {}", describe(&value))).map(|()| None),
+    }
+}
+
+/// A lua value as it's shown in errors
+fn describe(value: &mlua::Value) -> String {
+    match value {
+        mlua::Value::Nil => String::from("nil"),
+        mlua::Value::Boolean(b) => b.to_string(),
+        mlua::Value::Integer(i) => i.to_string(),
+        mlua::Value::Number(n) => n.to_string(),
+        mlua::Value::String(s) => format!("\"{}\"", s.to_string_lossy()),
+        mlua::Value::UserData(ud) if ud.is::<Layout>() => String::from("a layout"),
+        mlua::Value::UserData(ud) if ud.is::<Action>() => String::from("an action"),
+        mlua::Value::UserData(ud) if ud.is::<SpecialKey>() => String::from("a modifier"),
+        v => format!("a {}", v.type_name()),
+    }
+}
+
+/// The message of a lua error, without the "runtime error: " `Display` puts in front of it
+fn error_message(e: &mlua::Error) -> String {
+    match e {
+        mlua::Error::RuntimeError(message) => message.clone(),
+        e => e.to_string(),
+    }
+}
+
+pub fn load_config(path: &Path, reload: bool) -> Result<Config, ConfigError> {
+    // Nothing but the error that stopped the config is collected outside of `check_config`
+    load(path, reload, false).map_err(|mut problems| problems.remove(0))
+}
+
+/// Runs the config without applying it (like a reload, so startup programs aren't launched) and
+/// returns everything that is wrong with it
+pub fn check_config(path: &Path) -> Vec<ConfigError> {
+    let mut config = match load(path, true, true) {
+        Ok(config) => config,
+        Err(problems) => return problems,
+    };
+    let mut problems = config.problems.take().unwrap_or_default();
+
+    for (name, program) in [
+        ("terminal", &config.settings.terminal),
+        ("launcher", &config.settings.launcher),
+    ] {
+        if let Some(cmd) = program.split_whitespace().next()
+            && !program_exists(cmd)
+        {
+            problems.push(ConfigError::new(
+                path,
+                format!("the {name} `{cmd}` isn't installed (or isn't in PATH)"),
+            ));
+        }
+    }
+    problems
+}
+
+fn program_exists(cmd: &str) -> bool {
+    if cmd.contains('/') {
+        return Path::new(cmd).is_file();
+    }
+    std::env::var_os("PATH")
+        .is_some_and(|paths| std::env::split_paths(&paths).any(|p| p.join(cmd).is_file()))
+}
+
+/// On failure returns the problems collected while checking followed by the error that stopped
+/// the config
+fn load(path: &Path, reload: bool, checking: bool) -> Result<Config, Vec<ConfigError>> {
+    // Every load gets a fresh state, so a reload runs required modules again instead of reusing
+    // the ones cached in `package.loaded`
     let lua = Lua::new();
+    let config = Rc::new(RefCell::new(Config {
+        problems: checking.then(Vec::new),
+        ..Default::default()
+    }));
+
+    create_nwm_table(&lua, config.clone(), reload, checking).map_err(|()| {
+        vec![ConfigError::new(
+            path,
+            "failed to set up the `nwm` table, see the log",
+        )]
+    })?;
 
+    set_module_path(&lua, path).map_err(|e| {
+        vec![ConfigError::new(
+            path,
+            format!("failed to set `package.path`: {e}"),
+        )]
+    })?;
+
+    if checking {
+        stub_os_execute(&lua).map_err(|e| {
+            vec![ConfigError::new(
+                path,
+                format!("failed to replace `os.execute`: {e}"),
+            )]
+        })?;
+    }
+
+    let code = std::fs::read_to_string(path)
+        .map_err(|e| vec![ConfigError::new(path, format!("failed to read it: {e}"))])?;
+
+    lua.load(&code)
+        .set_name(format!("@{}", path.display()))
+        .exec()
+        .map_err(|e| {
+            let mut problems = config.borrow_mut().problems.take().unwrap_or_default();
+            problems.push(ConfigError::from_lua(&e, path));
+            problems
+        })?;
+    let mut config = config.borrow().clone();
+    config.runtime.lua = Some(lua);
+
+    {
+        let m_key = config.settings.master_key;
+        for b in &mut config.binds {
+            b.combo.prefixes.insert(0, m_key);
+        }
+    }
+
+    Ok(config)
+}
+
+/// Templates `require` tries for the modules of the config, in the format of `package.path`.
+/// `$NWM_CONFIG_PATH` if it's set, otherwise `?.lua` and `?/init.lua` next to config.lua
+pub fn module_path(config_path: &Path) -> String {
+    if let Ok(path) = std::env::var("NWM_CONFIG_PATH")
+        && !path.is_empty()
+    {
+        return path;
+    }
+    let dir = config_path
+        .parent()
+        .filter(|d| !d.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    format!("{0}/?.lua;{0}/?/init.lua", dir.display())
+}
+
+/// Directories `module_path` looks in
+pub fn module_dirs(config_path: &Path) -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    for template in module_path(config_path).split(';') {
+        let Some((prefix, _)) = template.split_once('?') else {
+            continue;
+        };
+        let dir = match prefix.strip_suffix('/') {
+            Some("") => PathBuf::from("/"),
+            Some(dir) => PathBuf::from(dir),
+            None => Path::new(prefix)
+                .parent()
+                .filter(|d| !d.as_os_str().is_empty())
+                .unwrap_or(Path::new("."))
+                .to_path_buf(),
+        };
+        if !dirs.contains(&dir) {
+            dirs.push(dir);
+        }
+    }
+    dirs
+}
+
+/// Puts the config's module path in front of the default one, so its modules win
+fn set_module_path(lua: &Lua, config_path: &Path) -> mlua::Result<()> {
+    let package = lua.globals().get::<mlua::Table>("package")?;
+    let default = package.get::<String>("path")?;
+    package.set("path", format!("{};{default}", module_path(config_path)))
+}
+
+/// Checking the config must not run anything, `os.execute` pretends every command succeeded
+fn stub_os_execute(lua: &Lua) -> mlua::Result<()> {
+    let os = lua.globals().get::<mlua::Table>("os")?;
+    os.set(
+        "execute",
+        lua.create_function(|_, _: Option<String>| Ok((true, "exit", 0)))?,
+    )
+}
+
+/// `checking` makes `nwm.spawn` a no-op, see `check_config`
+fn create_nwm_table(
+    lua: &Lua,
+    config: Rc<RefCell<Config>>,
+    reload: bool,
+    checking: bool,
+) -> Result<(), ()> {
     let nwm_table = lua.create_table().map_err(|e| {
         error!("Failed to create base configuration table: {e}");
     })?;
 
-    let config = Arc::new(Mutex::new(Config::default()));
-
     nwm_table
         .set(
             "set",
-            create_set_api(&lua, config.clone()).map_err(|e| {
+            create_set_api(lua, config.clone()).map_err(|e| {
                 error!("Failed to create `set` api table: {e}");
             })?,
         )
@@ -26,7 +337,7 @@ pub fn load_config(path: &std::path::Path, reload: bool) -> Result<Config, ()> {
     nwm_table
         .set(
             "action",
-            create_action_data(&lua).map_err(|e| {
+            create_action_data(lua).map_err(|e| {
                 error!("Failed to create `action` data table: {e}");
             })?,
         )
@@ -37,7 +348,7 @@ pub fn load_config(path: &std::path::Path, reload: bool) -> Result<Config, ()> {
     nwm_table
         .set(
             "bind",
-            create_bind_api(&lua, config.clone()).map_err(|e| {
+            create_bind_api(lua, config.clone()).map_err(|e| {
                 error!("Failed to create `bind` function: {e}");
             })?,
         )
@@ -45,10 +356,54 @@ pub fn load_config(path: &std::path::Path, reload: bool) -> Result<Config, ()> {
             error!("Failed to put `bind` function in the `nwm` table: {e}");
         })?;
 
+    nwm_table
+        .set(
+            "dispatch",
+            create_dispatch_api(lua, config.clone()).map_err(|e| {
+                error!("Failed to create `dispatch` function: {e}");
+            })?,
+        )
+        .map_err(|e| {
+            error!("Failed to put `dispatch` function in the `nwm` table: {e}");
+        })?;
+
+    nwm_table
+        .set(
+            "on",
+            create_on_api(lua, config.clone()).map_err(|e| {
+                error!("Failed to create `on` function: {e}");
+            })?,
+        )
+        .map_err(|e| {
+            error!("Failed to put `on` function in the `nwm` table: {e}");
+        })?;
+
+    nwm_table
+        .set(
+            "rule",
+            create_rule_api(lua, config.clone()).map_err(|e| {
+                error!("Failed to create `rule` function: {e}");
+            })?,
+        )
+        .map_err(|e| {
+            error!("Failed to put `rule` function in the `nwm` table: {e}");
+        })?;
+
+    nwm_table
+        .set(
+            "spawn",
+            create_spawn_api(lua, config.clone(), checking).map_err(|e| {
+                error!("Failed to create `spawn` function: {e}");
+            })?,
+        )
+        .map_err(|e| {
+            error!("Failed to put `spawn` function in the `nwm` table: {e}");
+        })?;
+
     nwm_table
         .set(
             "key",
-            create_key_consts(&lua).map_err(|e| {
+            create_key_consts(lua).map_err(|e| {
                 error!("Failed to create `key` table: {e}");
             })?,
         )
@@ -59,7 +414,7 @@ pub fn load_config(path: &std::path::Path, reload: bool) -> Result<Config, ()> {
     nwm_table
         .set(
             "modifier",
-            create_mod_consts(&lua).map_err(|e| {
+            create_mod_consts(lua).map_err(|e| {
                 error!("Failed to create `modifiers` table: {e}");
             })?,
         )
@@ -67,61 +422,80 @@ pub fn load_config(path: &std::path::Path, reload: bool) -> Result<Config, ()> {
             error!("Failed to put `modifiers` table in the `nwm` table: {e}");
         })?;
 
+    nwm_table
+        .set(
+            "layout",
+            create_layout_api(lua, config.clone()).map_err(|e| {
+                error!("Failed to create `layout` table: {e}");
+            })?,
+        )
+        .map_err(|e| {
+            error!("Failed to put `layout` table in the `nwm` table: {e}");
+        })?;
+
     nwm_table.set("first_boot", !reload).map_err(|e| {
         error!("Failed to set first_boot global var: {e}");
     })?;
 
     lua.globals().set("nwm", nwm_table).map_err(|e| {
         error!("Failed to put table `nwm` in the globals table: {e}");
-    })?;
-
-    let code = std::fs::read_to_string(path).map_err(|e| {
-        error!("Failed to read lua config file: {e}");
-    })?;
+    })
+}
 
-    lua.load(&code).set_name("config.lua").exec().map_err(|e| {
-        error!("Failed to execute lua config file: {e}");
-    })?;
-    let mut config = config.lock().unwrap().clone();
+/// Largest gap and border width nwm accepts, bigger values would break the layout math
+const MAX_GAP: usize = 1000;
+const MAX_BORDER_WIDTH: usize = 255;
 
-    {
-        let m_key = config.settings.master_key;
-        for b in &mut config.binds {
-            b.combo.prefixes.insert(0, m_key);
-        }
+/// `#rrggbb` or `#aarrggbb`
+fn parse_color(s: &str) -> Option<u32> {
+    let hex = s.strip_prefix('#')?;
+    if !matches!(hex.len(), 6 | 8) || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
     }
-
-    Ok(config)
+    u32::from_str_radix(hex, 16).ok()
 }
 
-fn create_set_api(lua: &Lua, config: Arc<Mutex<Config>>) -> mlua::Result<mlua::Table> {
+fn create_set_api(lua: &Lua, config: Rc<RefCell<Config>>) -> mlua::Result<mlua::Table> {
     let set_table = lua.create_table()?;
 
     macro_rules! set_usize {
-        ($name:literal, $field:ident) => {{
+        ($name:literal, $field:ident, $max:expr) => {{
             let cfg = config.clone();
             set_table.set(
                 $name,
-                lua.create_function(move |_, n: usize| {
-                    if stringify!($field) == "gap" {
-                        if n == 0 {
-                            return Ok(());
-                        }
+                lua.create_function(move |lua, n: mlua::Value| {
+                    let what = concat!($name, " expects a whole number");
+                    let Some(n) = arg::<usize>(lua, &cfg, n, what)? else {
+                        return Ok(());
+                    };
+                    if n > $max {
+                        return reject(
+                            lua,
+                            &cfg,
+                            format!("{} must be at most {}, got {n}", $name, $max),
+                        );
                     }
-                    cfg.lock().unwrap().settings.$field = n;
+                    cfg.borrow_mut().settings.$field = n;
                     Ok(())
                 })?,
             )?;
         }};
     }
 
-    macro_rules! set_string {
+    macro_rules! set_program {
         ($name:literal, $field:ident) => {{
             let cfg = config.clone();
             set_table.set(
                 $name,
-                lua.create_function(move |_, s: String| {
-                    cfg.lock().unwrap().settings.$field = s;
+                lua.create_function(move |lua, s: mlua::Value| {
+                    let what = concat!($name, " expects a command");
+                    let Some(s) = arg::<String>(lua, &cfg, s, what)? else {
+                        return Ok(());
+                    };
+                    if s.trim().is_empty() {
+                        return reject(lua, &cfg, format!("{} can't be empty", $name));
+                    }
+                    cfg.borrow_mut().settings.$field = s;
                     Ok(())
                 })?,
             )?;
@@ -133,30 +507,146 @@ fn create_set_api(lua: &Lua, config: Arc<Mutex<Config>>) -> mlua::Result<mlua::T
             let cfg = config.clone();
             set_table.set(
                 $name,
-                lua.create_function(move |_, n: String| {
-                    let color = u32::from_str_radix(&n[1..], 16).unwrap();
-                    cfg.lock().unwrap().settings.$field = color;
+                lua.create_function(move |lua, n: mlua::Value| {
+                    let what = concat!($name, " expects a color like \"#rrggbb\"");
+                    let Some(n) = arg::<String>(lua, &cfg, n, what)? else {
+                        return Ok(());
+                    };
+                    let Some(color) = parse_color(&n) else {
+                        return reject(
+                            lua,
+                            &cfg,
+                            format!("{}: `{n}` is not a color, expected #rrggbb", $name),
+                        );
+                    };
+                    cfg.borrow_mut().settings.$field = color;
                     Ok(())
                 })?,
             )?;
         }};
     }
 
-    set_usize!("gap", gap);
-    set_usize!("border_width", border_width);
+    macro_rules! set_bool {
+        ($name:literal, $field:ident) => {{
+            let cfg = config.clone();
+            set_table.set(
+                $name,
+                lua.create_function(move |lua, b: mlua::Value| {
+                    // Any lua value converts to a bool, only actual booleans are accepted
+                    let mlua::Value::Boolean(b) = b else {
+                        return reject(
+                            lua,
+                            &cfg,
+                            format!("{} expects true or false, got {}", $name, describe(&b)),
+                        );
+                    };
+                    cfg.borrow_mut().settings.$field = b;
+                    Ok(())
+                })?,
+            )?;
+        }};
+    }
+
+    set_usize!("gap", gap, MAX_GAP);
+    set_usize!("border_width", border_width, MAX_BORDER_WIDTH);
+    set_usize!("master_count", master_count, usize::MAX);
 
-    set_string!("terminal", terminal);
-    set_string!("launcher", launcher);
+    set_program!("terminal", terminal);
+    set_program!("launcher", launcher);
 
     set_color!("border_active_color", border_active_color);
     set_color!("border_inactive_color", border_inactive_color);
+    set_color!("border_unresponsive_color", border_unresponsive_color);
+
+    set_bool!("auto_reload", auto_reload);
+    set_bool!("reload_notice", reload_notice);
+
+    {
+        let cfg = config.clone();
+        set_table.set(
+            "master_ratio",
+            lua.create_function(move |lua, r: mlua::Value| {
+                let Some(r) = arg::<f32>(lua, &cfg, r, "master_ratio expects a number")? else {
+                    return Ok(());
+                };
+                if !(0.05..=0.95).contains(&r) {
+                    return reject(
+                        lua,
+                        &cfg,
+                        format!("master_ratio must be between 0.05 and 0.95, got {r}"),
+                    );
+                }
+                cfg.borrow_mut().settings.master_ratio = r;
+                Ok(())
+            })?,
+        )?;
+    }
+
+    {
+        let cfg = config.clone();
+        set_table.set(
+            "layout",
+            lua.create_function(move |lua, l: mlua::Value| {
+                let what = "layout expects one of nwm.layout.*";
+                if let Some(l) = arg::<Layout>(lua, &cfg, l, what)? {
+                    cfg.borrow_mut().settings.layout = l;
+                }
+                Ok(())
+            })?,
+        )?;
+    }
+
+    {
+        let cfg = config.clone();
+        set_table.set(
+            "workspace_layout",
+            lua.create_function(move |lua, (ws, l): (mlua::Value, mlua::Value)| {
+                let what = "workspace_layout expects a workspace number";
+                let ws = arg::<usize>(lua, &cfg, ws, what)?;
+                let what = "workspace_layout expects one of nwm.layout.*";
+                let (Some(ws), Some(l)) = (ws, arg::<Layout>(lua, &cfg, l, what)?) else {
+                    return Ok(());
+                };
+                if ws >= 10 {
+                    return reject(lua, &cfg, format!("there is no workspace {ws} (0-9)"));
+                }
+                cfg.borrow_mut().settings.workspace_layouts[ws] = Some(l);
+                Ok(())
+            })?,
+        )?;
+    }
+
+    {
+        let cfg = config.clone();
+        set_table.set(
+            "workspace_names",
+            lua.create_function(move |lua, names: mlua::Value| {
+                let what = "workspace_names expects a list of names";
+                let Some(names) = arg::<Vec<String>>(lua, &cfg, names, what)? else {
+                    return Ok(());
+                };
+                if names.len() > 10 {
+                    return reject(
+                        lua,
+                        &cfg,
+                        format!("there are only 10 workspaces, got {} names", names.len()),
+                    );
+                }
+                cfg.borrow_mut().settings.workspace_names = names;
+                Ok(())
+            })?,
+        )?;
+    }
 
     {
         let cfg = config.clone();
         set_table.set(
             "master_key",
-            lua.create_function(move |_, k: SpecialKey| {
-                cfg.lock().unwrap().settings.master_key = k;
+            lua.create_function(move |lua, k: mlua::Value| {
+                let what = "master_key expects one of nwm.modifier.*";
+                if let Some(k) = arg::<SpecialKey>(lua, &cfg, k, what)? {
+                    cfg.borrow_mut().settings.master_key = k;
+                }
                 Ok(())
             })?,
         )?;
@@ -176,16 +666,24 @@ fn create_action_data(lua: &Lua) -> mlua::Result<mlua::Table> {
     move_table.set("left", Action::MoveLeft)?;
     move_table.set("right", Action::MoveRight)?;
 
+    let layout_table = lua.create_table()?;
+    layout_table.set("next", Action::NextLayout)?;
+    layout_table.set("prev", Action::PrevLayout)?;
+
     action_table.set("focus", focus_table)?;
     action_table.set("move", move_table)?;
+    action_table.set("layout", layout_table)?;
     action_table.set("terminal", Action::Terminal)?;
     action_table.set("launcher", Action::Launcher)?;
 
     action_table.set("close", Action::CloseWindow)?;
+    action_table.set("kill", Action::KillWindow)?;
+    action_table.set("fullscreen", Action::ToggleFullscreen)?;
 
     action_table.set("prev_ws", Action::PrevWs)?;
     action_table.set("next_ws", Action::NextWs)?;
     action_table.set("reload", Action::ReloadConfig)?;
+    action_table.set("restart", Action::Restart)?;
     action_table.set("quit", Action::Quit)?;
     action_table.set("ws0", Action::Ws0)?;
     action_table.set("ws1", Action::Ws1)?;
@@ -212,12 +710,21 @@ fn create_action_data(lua: &Lua) -> mlua::Result<mlua::Table> {
     Ok(action_table)
 }
 
-fn create_bind_api(lua: &Lua, config: Arc<Mutex<Config>>) -> mlua::Result<mlua::Function> {
-    let bind = lua.create_function(move |_, (combo, action): (String, Action)| {
-        let combo = parse_keycombo(&combo)
-            .map_err(|_| mlua::Error::RuntimeError("invalid key combo".into()))?;
+fn create_bind_api(lua: &Lua, config: Rc<RefCell<Config>>) -> mlua::Result<mlua::Function> {
+    let bind = lua.create_function(move |lua, (combo, action): (mlua::Value, mlua::Value)| {
+        let what = "nwm.bind expects a key combo like \"Shift-q\"";
+        let Some(combo) = arg::<String>(lua, &config, combo, what)? else {
+            return Ok(());
+        };
+        let Ok(combo) = parse_keycombo(&combo) else {
+            return reject(lua, &config, format!("invalid key combo `{combo}`"));
+        };
+        let what = "nwm.bind expects one of nwm.action.* or a function";
+        let Some(action) = arg::<BindTarget>(lua, &config, action, what)? else {
+            return Ok(());
+        };
 
-        config.lock().unwrap().binds.push(Binding { combo, action });
+        config.borrow_mut().binds.push(Binding { combo, action });
 
         Ok(())
     })?;
@@ -225,6 +732,82 @@ fn create_bind_api(lua: &Lua, config: Arc<Mutex<Config>>) -> mlua::Result<mlua::
     Ok(bind)
 }
 
+fn create_dispatch_api(lua: &Lua, config: Rc<RefCell<Config>>) -> mlua::Result<mlua::Function> {
+    let dispatch = lua.create_function(move |lua, action: mlua::Value| {
+        let what = "nwm.dispatch expects one of nwm.action.*";
+        if let Some(action) = arg::<Action>(lua, &config, action, what)? {
+            config.borrow().runtime.dispatched.borrow_mut().push(action);
+        }
+        Ok(())
+    })?;
+
+    Ok(dispatch)
+}
+
+fn create_on_api(lua: &Lua, config: Rc<RefCell<Config>>) -> mlua::Result<mlua::Function> {
+    let on = lua.create_function(move |lua, (event, f): (mlua::Value, mlua::Value)| {
+        let event = arg::<String>(lua, &config, event, "nwm.on expects an event name")?;
+        let f = arg::<mlua::Function>(lua, &config, f, "nwm.on expects a function")?;
+        let (Some(event), Some(f)) = (event, f) else {
+            return Ok(());
+        };
+        let Ok(hook) = event.parse::<Hook>() else {
+            return reject(
+                lua,
+                &config,
+                format!(
+                    "unknown event `{event}`, expected one of window_open, window_close, focus, workspace, reload"
+                ),
+            );
+        };
+        config
+            .borrow_mut()
+            .runtime
+            .hooks
+            .entry(hook)
+            .or_default()
+            .push(f);
+        Ok(())
+    })?;
+
+    Ok(on)
+}
+
+fn create_rule_api(lua: &Lua, config: Rc<RefCell<Config>>) -> mlua::Result<mlua::Function> {
+    let rule = lua.create_function(move |lua, rule: mlua::Value| {
+        match Rule::from_lua(rule, lua) {
+            Ok(rule) => config.borrow_mut().settings.rules.push(rule),
+            Err(e) => return reject(lua, &config, error_message(&e)),
+        }
+        Ok(())
+    })?;
+
+    Ok(rule)
+}
+
+fn create_spawn_api(
+    lua: &Lua,
+    config: Rc<RefCell<Config>>,
+    checking: bool,
+) -> mlua::Result<mlua::Function> {
+    let spawn = lua.create_function(move |lua, cmd: mlua::Value| {
+        let Some(cmd) = arg::<String>(lua, &config, cmd, "nwm.spawn expects a command")? else {
+            return Ok(());
+        };
+        if checking {
+            return Ok(());
+        }
+        std::process::Command::new("sh")
+            .arg("-c")
+            .arg(&cmd)
+            .spawn()
+            .map_err(|e| mlua::Error::RuntimeError(format!("Failed to spawn `{cmd}`: {e}")))?;
+        Ok(())
+    })?;
+
+    Ok(spawn)
+}
+
 fn create_mod_consts(lua: &Lua) -> mlua::Result<mlua::Table> {
     let table = lua.create_table()?;
     table.set("Alt", SpecialKey::Alt)?;
@@ -234,6 +817,35 @@ fn create_mod_consts(lua: &Lua) -> mlua::Result<mlua::Table> {
     Ok(table)
 }
 
+fn create_layout_api(lua: &Lua, config: Rc<RefCell<Config>>) -> mlua::Result<mlua::Table> {
+    let table = lua.create_table()?;
+    table.set(
+        "register",
+        lua.create_function(move |lua, (name, function): (mlua::Value, mlua::Value)| {
+            let what = "nwm.layout.register expects a name";
+            let name = arg::<String>(lua, &config, name, what)?;
+            let what = "nwm.layout.register expects a function";
+            let function = arg::<mlua::Function>(lua, &config, function, what)?;
+            let (Some(name), Some(function)) = (name, function) else {
+                return Ok(None);
+            };
+            let layouts = &mut config.borrow_mut().settings.custom_layouts;
+            layouts.retain(|l| l.name != name);
+            layouts.push(CustomLayout {
+                name: name.clone(),
+                function,
+            });
+            Ok(Some(Layout::Custom(name)))
+        })?,
+    )?;
+    table.set("horizontal", Layout::Horizontal)?;
+    table.set("vertical", Layout::Vertical)?;
+    table.set("master_stack", Layout::MasterStack)?;
+    table.set("grid", Layout::Grid)?;
+    table.set("monocle", Layout::Monocle)?;
+    Ok(table)
+}
+
 fn create_key_consts(lua: &Lua) -> mlua::Result<mlua::Table> {
     let table = lua.create_table()?;
     table.set("Space", "Space")?;
@@ -283,36 +895,80 @@ fn parse_keycombo(s: &str) -> Result<KeyCombo, ()> {
 pub struct Config {
     pub settings: Settings,
     pub binds: Vec<Binding>,
+    pub runtime: Runtime,
+    /// `Some` while only checking the config, bad settings, binds and rules are collected here
+    /// instead of raising lua errors
+    pub problems: Option<Vec<ConfigError>>,
+}
+
+/// Everything lua related that nwm has to keep around after config.lua finished running
+#[derive(Debug, Clone, Default)]
+pub struct Runtime {
+    /// The interpreter that ran config.lua, it has to outlive every lua function stored in the
+    /// config (custom layouts, bindings, hooks, ...). `None` for the fallback config
+    pub lua: Option<Lua>,
+    /// Actions queued with `nwm.dispatch` from inside of lua callbacks, nwm runs them once the
+    /// callback returns
+    pub dispatched: Rc<RefCell<Vec<Action>>>,
+    /// Functions registered with `nwm.on`
+    pub hooks: HashMap<Hook, Vec<mlua::Function>>,
+}
+
+/// Events lua can subscribe to with `nwm.on(name, function)`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Hook {
+    WindowOpen,
+    WindowClose,
+    Focus,
+    Workspace,
+    Reload,
+}
+
+impl std::str::FromStr for Hook {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "window_open" => Ok(Self::WindowOpen),
+            "window_close" => Ok(Self::WindowClose),
+            "focus" => Ok(Self::Focus),
+            "workspace" => Ok(Self::Workspace),
+            "reload" => Ok(Self::Reload),
+            _ => Err(()),
+        }
+    }
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             settings: Settings::default(),
+            runtime: Runtime::default(),
+            problems: None,
             binds: vec![
                 Binding {
-                    action: Action::Terminal,
+                    action: BindTarget::Action(Action::Terminal),
                     combo: KeyCombo {
                         prefixes: vec![SpecialKey::Alt],
                         key: Key::Return,
                     },
                 },
                 Binding {
-                    action: Action::Launcher,
+                    action: BindTarget::Action(Action::Launcher),
                     combo: KeyCombo {
                         prefixes: vec![SpecialKey::Alt],
                         key: Key::Space,
                     },
                 },
                 Binding {
-                    action: Action::CloseWindow,
+                    action: BindTarget::Action(Action::CloseWindow),
                     combo: KeyCombo {
                         prefixes: vec![SpecialKey::Alt],
                         key: Key::Char('w'),
                     },
                 },
                 Binding {
-                    action: Action::Quit,
+                    action: BindTarget::Action(Action::Quit),
                     combo: KeyCombo {
                         prefixes: vec![SpecialKey::Alt, SpecialKey::Shift],
                         key: Key::Char('q'),
@@ -332,6 +988,34 @@ pub struct Settings {
     pub border_width: usize,
     pub border_active_color: u32,
     pub border_inactive_color: u32,
+    pub border_unresponsive_color: u32,
+    /// Reload the config when a lua file in its directory changes
+    pub auto_reload: bool,
+    /// Show failed reloads with notify-send
+    pub reload_notice: bool,
+    pub master_ratio: f32,
+    pub master_count: usize,
+    pub layout: Layout,
+    pub workspace_layouts: [Option<Layout>; 10],
+    /// Names of the first workspaces, the rest are named after their index
+    pub workspace_names: Vec<String>,
+    pub custom_layouts: Vec<CustomLayout>,
+    pub rules: Vec<Rule>,
+}
+
+impl Settings {
+    pub fn layout_for(&self, ws: usize) -> Layout {
+        self.workspace_layouts[ws]
+            .clone()
+            .unwrap_or_else(|| self.layout.clone())
+    }
+
+    pub fn workspace_name(&self, ws: usize) -> String {
+        self.workspace_names
+            .get(ws)
+            .cloned()
+            .unwrap_or_else(|| ws.to_string())
+    }
 }
 
 impl Default for Settings {
@@ -344,14 +1028,196 @@ impl Default for Settings {
             border_width: 2,
             border_active_color: 0xffffffff,
             border_inactive_color: 0xff181818,
+            border_unresponsive_color: 0xffcc2222,
+            auto_reload: false,
+            reload_notice: false,
+            master_ratio: 0.55,
+            master_count: 1,
+            layout: Layout::default(),
+            workspace_layouts: Default::default(),
+            workspace_names: Vec::new(),
+            custom_layouts: Vec::new(),
+            rules: Vec::new(),
         }
     }
 }
 
+/// Registered with `nwm.rule{...}`, decides how a window gets managed when it's mapped. The
+/// `class`, `instance`, `title` and `type` fields are lua patterns, every one that is set has to
+/// match. Everything else is `None` when the rule doesn't care about it
+#[derive(Debug, Clone, Default)]
+pub struct Rule {
+    pub class: Option<String>,
+    pub instance: Option<String>,
+    pub title: Option<String>,
+    pub window_type: Option<String>,
+
+    pub workspace: Option<usize>,
+    pub floating: Option<bool>,
+    pub geometry: Option<(i16, i16, i16, i16)>,
+    pub border_width: Option<usize>,
+    pub focus: Option<bool>,
+}
+
+/// What rules get matched against
 #[derive(Debug, Clone, Default)]
+pub struct WindowProps {
+    pub class: Option<String>,
+    pub instance: Option<String>,
+    pub title: Option<String>,
+    /// _NET_WM_WINDOW_TYPE without the prefix and in lowercase (`normal`, `dialog`, ...)
+    pub types: Vec<String>,
+}
+
+impl Rule {
+    const FIELDS: [&str; 9] = [
+        "class",
+        "instance",
+        "title",
+        "type",
+        "workspace",
+        "floating",
+        "geometry",
+        "border",
+        "focus",
+    ];
+
+    pub fn matches(&self, lua: &Lua, props: &WindowProps) -> mlua::Result<bool> {
+        let find = lua
+            .globals()
+            .get::<mlua::Table>("string")?
+            .get::<mlua::Function>("find")?;
+        let matches = |pattern: &Option<String>, value: Option<&String>| -> mlua::Result<bool> {
+            match (pattern, value) {
+                (None, _) => Ok(true),
+                (Some(_), None) => Ok(false),
+                (Some(p), Some(v)) => {
+                    Ok(!find.call::<mlua::Value>((v.as_str(), p.as_str()))?.is_nil())
+                }
+            }
+        };
+
+        let type_matches = match &self.window_type {
+            None => true,
+            Some(_) => {
+                let mut any = false;
+                for t in &props.types {
+                    any |= matches(&self.window_type, Some(t))?;
+                }
+                any
+            }
+        };
+
+        Ok(type_matches
+            && matches(&self.class, props.class.as_ref())?
+            && matches(&self.instance, props.instance.as_ref())?
+            && matches(&self.title, props.title.as_ref())?)
+    }
+
+    /// Overrides every effect of `self` which is also set in `other`
+    pub fn merge(&mut self, other: &Rule) {
+        self.workspace = other.workspace.or(self.workspace);
+        self.floating = other.floating.or(self.floating);
+        self.geometry = other.geometry.or(self.geometry);
+        self.border_width = other.border_width.or(self.border_width);
+        self.focus = other.focus.or(self.focus);
+    }
+}
+
+impl mlua::FromLua for Rule {
+    fn from_lua(value: mlua::Value, _lua: &Lua) -> mlua::Result<Self> {
+        let mlua::Value::Table(t) = value else {
+            return Err(mlua::Error::FromLuaConversionError {
+                from: value.type_name(),
+                to: "Rule".to_string(),
+                message: Some(
+                    "nwm.rule expects a table like { class = \"firefox\", workspace = 2 }"
+                        .to_string(),
+                ),
+            });
+        };
+
+        for pair in t.pairs::<mlua::Value, mlua::Value>() {
+            let (k, _) = pair?;
+            let known = match &k {
+                mlua::Value::String(s) => s.to_str().is_ok_and(|s| Self::FIELDS.contains(&&*s)),
+                _ => false,
+            };
+            if !known {
+                return Err(mlua::Error::RuntimeError(format!(
+                    "unknown rule field `{}`, expected one of {}",
+                    k.to_string()?,
+                    Self::FIELDS.join(", ")
+                )));
+            }
+        }
+
+        let workspace = t.get::<Option<usize>>("workspace")?;
+        if let Some(ws) = workspace
+            && ws >= 10
+        {
+            return Err(mlua::Error::RuntimeError(format!(
+                "there is no workspace {ws} (0-9)"
+            )));
+        }
+
+        let geometry = match t.get::<Option<mlua::Table>>("geometry")? {
+            None => None,
+            Some(g) => {
+                let get = |name: &str, i: usize| -> mlua::Result<i16> {
+                    g.get::<Option<i16>>(name)?
+                        .map_or_else(|| g.get::<i16>(i), Ok)
+                };
+                Some((get("x", 1)?, get("y", 2)?, get("w", 3)?, get("h", 4)?))
+            }
+        };
+
+        let rule = Rule {
+            class: t.get("class")?,
+            instance: t.get("instance")?,
+            title: t.get("title")?,
+            window_type: t.get("type")?,
+            workspace,
+            floating: t.get("floating")?,
+            geometry,
+            border_width: t.get("border")?,
+            focus: t.get("focus")?,
+        };
+
+        if rule.class.is_none()
+            && rule.instance.is_none()
+            && rule.title.is_none()
+            && rule.window_type.is_none()
+        {
+            return Err(mlua::Error::RuntimeError(
+                "a rule needs at least one of class, instance, title or type to match".to_string(),
+            ));
+        }
+
+        Ok(rule)
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Binding {
     pub combo: KeyCombo,
-    pub action: Action,
+    pub action: BindTarget,
+}
+
+/// What a key binding does, either one of the built-in actions or a lua function
+#[derive(Debug, Clone)]
+pub enum BindTarget {
+    Action(Action),
+    Lua(mlua::Function),
+}
+
+impl mlua::FromLua for BindTarget {
+    fn from_lua(value: mlua::Value, lua: &Lua) -> mlua::Result<Self> {
+        match value {
+            mlua::Value::Function(f) => Ok(Self::Lua(f)),
+            v => Ok(Self::Action(Action::from_lua(v, lua)?)),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Default)]
@@ -374,7 +1240,7 @@ impl Key {
     pub fn into_x11rb(self) -> u32 {
         match self {
             Self::Escape => crate::better_x11rb::XK_ESCAPE,
-            Self::Space => ' ' as u32,
+            Self::Space => crate::better_x11rb::XK_SPACE,
             Self::Return => crate::better_x11rb::XK_RETURN,
             Self::Tab => crate::better_x11rb::XK_TAB,
             Self::Char(c) => c as u32,
@@ -392,9 +1258,14 @@ pub enum Action {
     Launcher,
     Terminal,
     CloseWindow,
+    KillWindow,
+    ToggleFullscreen,
     NextWs,
     PrevWs,
+    NextLayout,
+    PrevLayout,
     ReloadConfig,
+    Restart,
     Quit,
     Ws0,
     Ws1,
@@ -422,7 +1293,7 @@ impl mlua::UserData for Action {}
 impl mlua::FromLua for Action {
     fn from_lua(value: mlua::Value, _lua: &Lua) -> mlua::Result<Self> {
         match value {
-            mlua::Value::UserData(ud) => Ok(*ud.borrow().unwrap()),
+            mlua::Value::UserData(ud) => Ok(*ud.borrow::<Action>()?),
             _ => Err(mlua::Error::FromLuaConversionError {
                 from: "Lua side action constant",
                 to: "Rust size action constant".to_string(),
@@ -445,7 +1316,7 @@ impl mlua::UserData for SpecialKey {}
 impl mlua::FromLua for SpecialKey {
     fn from_lua(value: mlua::Value, _lua: &Lua) -> mlua::Result<Self> {
         match value {
-            mlua::Value::UserData(ud) => Ok(*ud.borrow().unwrap()),
+            mlua::Value::UserData(ud) => Ok(*ud.borrow::<SpecialKey>()?),
             _ => Err(mlua::Error::FromLuaConversionError {
                 from: "Lua side modifier key constant",
                 to: "Rust size modifier key constant".to_string(),
@@ -465,3 +1336,180 @@ impl std::fmt::Display for SpecialKey {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parts(e: &ConfigError) -> (String, Option<usize>, String) {
+        (e.file.display().to_string(), e.line, e.message.clone())
+    }
+
+    /// The error of config.lua calling a rust function that failed with `cause` on line 2
+    fn callback_error(cause: mlua::Error) -> mlua::Error {
+        let lua = Lua::new();
+        let f = lua
+            .create_function(move |_, ()| Err::<(), _>(cause.clone()))
+            .unwrap();
+        lua.globals().set("f", f).unwrap();
+        lua.load("\nf()")
+            .set_name("@config.lua")
+            .exec()
+            .unwrap_err()
+    }
+
+    /// Runs `code` as a config only being checked, returning the lines and messages it reported
+    fn check(name: &str, code: &str) -> Vec<(Option<usize>, String)> {
+        let path = std::env::temp_dir().join(format!("nwm-test-{}-{name}.lua", std::process::id()));
+        std::fs::write(&path, code).unwrap();
+        let problems = match load(&path, true, true) {
+            Ok(mut config) => config.problems.take().unwrap(),
+            Err(problems) => problems,
+        };
+        _ = std::fs::remove_file(&path);
+        problems
+            .iter()
+            .map(|e| (e.line, e.message.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn parses_colors() {
+        let cases = [
+            ("#ff0000", Some(0xff0000)),
+            ("#80FF00aa", Some(0x80ff00aa)),
+            ("#000000", Some(0)),
+            ("ff0000", None),
+            ("#fff", None),
+            ("#ff00000", None),
+            ("#gg0000", None),
+            ("#+f0000", None),
+            ("", None),
+        ];
+        for (s, color) in cases {
+            assert_eq!(parse_color(s), color, "{s:?}");
+        }
+    }
+
+    #[test]
+    fn splits_locations() {
+        let cases = [
+            ("config.lua:3: boom", Some(("config.lua", 3, "boom"))),
+            (
+                "/a:b/c.lua:12:  spaced ",
+                Some(("/a:b/c.lua", 12, "spaced")),
+            ),
+            ("[string \"x\"]:1: m", Some(("[string \"x\"]", 1, "m"))),
+            ("config.lua:x: boom", None),
+            ("no location", None),
+            ("", None),
+        ];
+        for (s, location) in cases {
+            assert_eq!(split_location(s), location, "{s:?}");
+        }
+    }
+
+    #[test]
+    fn finds_the_location_of_lua_errors() {
+        let file = Path::new("config.lua");
+        let located = ConfigError {
+            file: PathBuf::from("mods/a.lua"),
+            line: Some(4),
+            message: String::from("gap must be at most 1000, got 5000"),
+        };
+        let cases = [
+            (
+                mlua::Error::RuntimeError(String::from(
+                    "config.lua:3: boom\nstack traceback:\n\t[C]: in ?",
+                )),
+                ("config.lua", Some(3), "boom"),
+            ),
+            (
+                mlua::Error::RuntimeError(String::from("no location")),
+                ("config.lua", None, "no location"),
+            ),
+            (
+                mlua::Error::SyntaxError {
+                    message: String::from(
+                        "error loading module 'm' from file './m.lua':\n\t./m.lua:2: unexpected symbol",
+                    ),
+                    incomplete_input: false,
+                },
+                ("./m.lua", Some(2), "unexpected symbol"),
+            ),
+            (
+                mlua::Error::external(located.clone()),
+                ("mods/a.lua", Some(4), "gap must be at most 1000, got 5000"),
+            ),
+            (
+                callback_error(mlua::Error::RuntimeError(String::from("bad"))),
+                ("config.lua", Some(2), "bad"),
+            ),
+            (
+                callback_error(mlua::Error::external(located)),
+                ("mods/a.lua", Some(4), "gap must be at most 1000, got 5000"),
+            ),
+        ];
+        for (e, (f, line, message)) in cases {
+            assert_eq!(
+                parts(&ConfigError::from_lua(&e, file)),
+                (f.to_string(), line, message.to_string()),
+                "{e:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn checking_reports_every_problem() {
+        let code = r#"
+            nwm.set.master_key(nwm.layout.grid)
+            nwm.set.gap(-1)
+            nwm.on("typo", function() end)
+            nwm.set.workspace_layout(3, "grid")
+            nwm.set.auto_reload("yes")
+            nwm.bind("Shift-", nwm.action.close)
+            nwm.rule({ workspace = 2 })
+            nwm.set.gap(0)
+        "#;
+        let problems = check("every-problem", code);
+        let expected = [
+            (2, "master_key expects one of nwm.modifier.*, got a layout"),
+            (3, "gap expects a whole number, got -1"),
+            (
+                4,
+                "unknown event `typo`, expected one of window_open, window_close, focus, workspace, reload",
+            ),
+            (5, "workspace_layout expects one of nwm.layout.*, got \"grid\""),
+            (6, "auto_reload expects true or false, got \"yes\""),
+            (7, "invalid key combo `Shift-`"),
+            (
+                8,
+                "a rule needs at least one of class, instance, title or type to match",
+            ),
+        ]
+        .map(|(line, message)| (Some(line), message.to_string()));
+        assert_eq!(problems, expected);
+    }
+
+    #[test]
+    fn wrong_types_are_errors_outside_of_checking() {
+        let path = std::env::temp_dir().join(format!("nwm-test-{}-load.lua", std::process::id()));
+        std::fs::write(
+            &path,
+            "nwm.set.gap(0)\nnwm.set.master_key(nwm.layout.grid)\n",
+        )
+        .unwrap();
+        let error = load_config(&path, true).unwrap_err();
+        _ = std::fs::remove_file(&path);
+        assert_eq!(error.line, Some(2));
+        assert_eq!(
+            error.message,
+            "master_key expects one of nwm.modifier.*, got a layout"
+        );
+
+        std::fs::write(&path, "nwm.set.gap(0)\n").unwrap();
+        let config = load_config(&path, true).unwrap();
+        _ = std::fs::remove_file(&path);
+        assert_eq!(config.settings.gap, 0);
+    }
+}
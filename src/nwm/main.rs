@@ -1,13 +1,39 @@
 mod better_x11rb;
+mod cli;
+mod config_watch;
+mod ipc;
+mod layout;
 mod lua_cfg;
 mod multi_log;
 mod nw_log_connection;
-
-use std::{collections::HashMap, process::Command};
+mod session;
+
+use std::{
+    collections::{HashMap, HashSet},
+    os::unix::process::CommandExt,
+    path::PathBuf,
+    process::{Command, ExitCode},
+    time::{Duration, Instant},
+};
 
 use better_x11rb::WindowId;
+use layout::{Layout, LayoutParams, Rect};
+
+use log::{error, info, warn};
+use nix::{
+    errno::Errno,
+    poll::{PollFd, PollFlags, PollTimeout, poll},
+    sys::signal::{Signal, kill},
+    unistd::Pid,
+};
 
-use log::{info, warn};
+/// `WM_STATE` values of windows that are (or were, before a crash) managed
+const WM_STATE_NORMAL: u32 = 1;
+const WM_STATE_ICONIC: u32 = 3;
+/// How long a window gets to answer `_NET_WM_PING` before it's considered hung
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+/// Editors save in bursts of writes, the config is reloaded once they stopped for this long
+const RELOAD_DEBOUNCE: Duration = Duration::from_millis(300);
 
 struct Nwm {
     x11: better_x11rb::X11RB,
@@ -21,18 +47,61 @@ struct Nwm {
     window_type_normal_atom: Option<Atom>,
     window_type_dock_atom: Option<Atom>,
     strut_partial_atom: Option<Atom>,
+    net_wm_name_atom: Option<Atom>,
+    utf8_string_atom: Option<Atom>,
     active_desktop_atom: Option<Atom>,
+    wm_protocols_atom: Option<Atom>,
+    wm_delete_window_atom: Option<Atom>,
+    net_wm_ping_atom: Option<Atom>,
+    net_wm_pid_atom: Option<Atom>,
+    net_wm_desktop_atom: Option<Atom>,
+    wm_state_atom: Option<Atom>,
+    net_client_list_atom: Option<Atom>,
+    net_client_list_stacking_atom: Option<Atom>,
+    net_supported_atom: Option<Atom>,
+    net_supporting_wm_check_atom: Option<Atom>,
+    net_number_of_desktops_atom: Option<Atom>,
+    net_desktop_names_atom: Option<Atom>,
+    net_desktop_viewport_atom: Option<Atom>,
+    net_active_window_atom: Option<Atom>,
+    net_close_window_atom: Option<Atom>,
+    net_wm_state_atom: Option<Atom>,
+    net_wm_state_focused_atom: Option<Atom>,
+    net_wm_state_fullscreen_atom: Option<Atom>,
+    /// Windows covering the whole screen, they keep their tiled slot or floating geometry to go
+    /// back to
+    fullscreen: HashSet<WindowId>,
+    /// The window in `_NET_ACTIVE_WINDOW`, carries `_NET_WM_STATE_FOCUSED`
+    active_window: Option<WindowId>,
+    /// Managed windows in the order they were first mapped, for `_NET_CLIENT_LIST`
+    clients: Vec<WindowId>,
+    /// Managed windows from bottom to top in the order `raise` left them, for
+    /// `_NET_CLIENT_LIST_STACKING`
+    stacking: Vec<WindowId>,
+    /// Windows that were pinged after being asked to close, with the time they have to answer
+    pings: HashMap<WindowId, Instant>,
+    /// Windows that didn't answer a ping in time, closing them again kills them
+    unresponsive: HashSet<WindowId>,
     struts: HashMap<WindowId, Strut>,
+    /// Where `layout` last put the tiled windows of the current workspace
+    tiled_rects: Vec<(WindowId, Rect)>,
 
-    gap: u8,
     binds: Vec<Bind>,
-    terminal: String,
-    launcher: String,
-    border_width: u8,
-    active_border_color: u32,
-    inactive_border_color: u32,
+    settings: lua_cfg::Settings,
+    lua: lua_cfg::Runtime,
     config_path: std::path::PathBuf,
     suppress_cursor_focus: bool,
+    /// Set while hooks run, see `fire_hook`
+    in_hook: bool,
+    /// Set by `Action::Restart`, `run` restarts once the events and ipc requests that were read
+    /// are handled, so e.g. `nwmctl restart` gets its reply first
+    restart_pending: bool,
+    ipc: Option<ipc::IpcServer>,
+    display_name: String,
+    /// Only exists while `settings.auto_reload` is on
+    config_watch: Option<config_watch::ConfigWatch>,
+    /// When the config gets reloaded after it changed on disk
+    reload_at: Option<Instant>,
 }
 
 #[derive(Debug, Copy, Clone, Default)]
@@ -48,6 +117,7 @@ struct Workspace {
     windows: Vec<WindowId>,
     focused: Option<WindowId>,
     floating: HashMap<WindowId, Geometry>,
+    layout: Layout,
 }
 
 impl Workspace {
@@ -55,6 +125,7 @@ impl Workspace {
         &self.windows
     }
 
+    #[allow(dead_code)]
     pub fn windows_mut(&mut self) -> &mut Vec<WindowId> {
         &mut self.windows
     }
@@ -120,6 +191,12 @@ impl Workspace {
         if let Some(f) = self.focused
             && let Some(p) = self.windows().iter().position(|x| *x == f)
         {
+            // In monocle only one window is visible, so wrap around instead of getting stuck
+            if self.layout == Layout::Monocle {
+                let new_pos = (p + self.window_count() - 1) % self.window_count();
+                self.focused = Some(self.windows[new_pos]);
+                return;
+            }
             let new_pos = p.saturating_sub(1).clamp(0, self.window_count() - 1);
             self.focused = Some(self.windows[new_pos]);
         }
@@ -129,6 +206,11 @@ impl Workspace {
         if let Some(f) = self.focused
             && let Some(p) = self.windows().iter().position(|x| *x == f)
         {
+            if self.layout == Layout::Monocle {
+                let new_pos = (p + 1) % self.window_count();
+                self.focused = Some(self.windows[new_pos]);
+                return;
+            }
             let new_pos = p.saturating_add(1).clamp(0, self.window_count() - 1);
             self.focused = Some(self.windows[new_pos]);
         }
@@ -171,7 +253,6 @@ impl Workspace {
     }
 }
 
-#[allow(dead_code)]
 struct Strut {
     left: u32,
     right: u32,
@@ -209,10 +290,16 @@ impl From<[u32; 12]> for Strut {
 
 #[derive(Debug, Clone)]
 struct Bind {
-    action: fn(&mut Nwm),
+    action: BindAction,
     bind: lua_cfg::KeyCombo,
 }
 
+#[derive(Debug, Clone)]
+enum BindAction {
+    Builtin(fn(&mut Nwm)),
+    Lua(mlua::Function),
+}
+
 fn keycombo_mask(kc: &lua_cfg::KeyCombo) -> u16 {
     let mut mask = 0;
     for m in &kc.prefixes {
@@ -241,18 +328,13 @@ impl Bind {
             return;
         }
 
-        (self.action)(nwm);
+        match &self.action {
+            BindAction::Builtin(f) => f(nwm),
+            BindAction::Lua(f) => nwm.run_lua_callback(f, ()),
+        }
     }
 }
 
-#[derive(Debug, Clone, Copy, Default)]
-struct Rect {
-    x: i16,
-    y: i16,
-    w: i16,
-    h: i16,
-}
-
 #[derive(Debug, Clone, Copy, Default)]
 struct Reserve {
     x0: u32,
@@ -265,8 +347,9 @@ use x11rb::{
     protocol::{
         Event,
         xproto::{
-            Atom, AtomEnum, ChangeWindowAttributesAux, ConfigureWindowAux, ConnectionExt,
-            EventMask, KeyPressEvent, MapRequestEvent, ModMask, PropMode, UnmapNotifyEvent,
+            Atom, AtomEnum, ChangeWindowAttributesAux, ClientMessageEvent, ConfigureWindowAux,
+            ConnectionExt, EventMask, KeyPressEvent, MapRequestEvent, MapState, ModMask, PropMode,
+            UnmapNotifyEvent,
         },
     },
     wrapper::ConnectionExt as OtherConnExt,
@@ -281,8 +364,12 @@ fn action_to_fn(action: lua_cfg::Action) -> fn(&mut Nwm) {
         lua_cfg::Action::Launcher => Nwm::launcher,
         lua_cfg::Action::Terminal => Nwm::terminal,
         lua_cfg::Action::CloseWindow => Nwm::close_focused,
+        lua_cfg::Action::KillWindow => Nwm::kill_focused,
+        lua_cfg::Action::ToggleFullscreen => Nwm::toggle_fullscreen,
         lua_cfg::Action::NextWs => Nwm::focus_next_ws,
         lua_cfg::Action::PrevWs => Nwm::focus_prev_ws,
+        lua_cfg::Action::NextLayout => Nwm::next_layout,
+        lua_cfg::Action::PrevLayout => Nwm::prev_layout,
         lua_cfg::Action::ReloadConfig => Nwm::reload_config,
         lua_cfg::Action::Ws0 => |nwm: &mut Nwm| {
             nwm.switch_ws(0);
@@ -344,6 +431,9 @@ fn action_to_fn(action: lua_cfg::Action) -> fn(&mut Nwm) {
         lua_cfg::Action::MoveToWs9 => |nwm: &mut Nwm| {
             nwm.move_focused_to_ws(9);
         },
+        lua_cfg::Action::Restart => |nwm: &mut Nwm| {
+            nwm.restart_pending = true;
+        },
         lua_cfg::Action::Quit => |nwm: &mut Nwm| {
             nwm.running = false;
         },
@@ -354,9 +444,7 @@ impl Nwm {
     fn apply_lua_config(
         conf: lua_cfg::Config,
         x11: &mut better_x11rb::X11RB,
-    ) -> (u8, Vec<Bind>, String, String, u32, u32, u8) {
-        let settings = conf.settings;
-
+    ) -> (Vec<Bind>, lua_cfg::Settings) {
         let mut binds = Vec::new();
 
         for b in conf.binds {
@@ -374,105 +462,288 @@ impl Nwm {
 
             x11.grab_key(mask, b.combo.key.into_x11rb()).unwrap();
 
+            let action = match b.action {
+                lua_cfg::BindTarget::Action(a) => BindAction::Builtin(action_to_fn(a)),
+                lua_cfg::BindTarget::Lua(f) => BindAction::Lua(f),
+            };
             binds.push(Bind {
-                action: action_to_fn(b.action),
+                action,
                 bind: b.combo,
             });
         }
 
-        (
-            settings.gap as u8,
-            binds,
-            settings.terminal,
-            settings.launcher,
-            settings.border_active_color,
-            settings.border_inactive_color,
-            settings.border_width as u8,
-        )
+        (binds, conf.settings)
+    }
+
+    /// Gives the workspaces the layout the config sets for them. On a reload (`old` being the
+    /// settings before it) only the ones whose configured layout changed, so layouts picked with
+    /// `layout.next`/`prev` survive saving config.lua, unless the config stopped registering them
+    fn apply_workspace_layouts(&mut self, old: Option<&lua_cfg::Settings>) {
+        let custom = &self.settings.custom_layouts;
+        for (i, ws) in self.workspaces.iter_mut().enumerate() {
+            let layout = self.settings.layout_for(i);
+            let unregistered = matches!(
+                &ws.layout,
+                Layout::Custom(name) if !custom.iter().any(|c| &c.name == name)
+            );
+            if unregistered || old.is_none_or(|old| old.layout_for(i) != layout) {
+                ws.layout = layout;
+            }
+        }
+    }
+
+    /// Runs a lua callback (bindings, ...) and then every action it queued with `nwm.dispatch`.
+    /// Errors raised by the callback only get logged
+    fn run_lua_callback(&mut self, f: &mlua::Function, args: impl mlua::IntoLuaMulti) {
+        if let Err(e) = self.publish_lua_state() {
+            warn!("Failed to update `nwm.state` before running a lua callback: {e}");
+        }
+        self.lua.dispatched.borrow_mut().clear();
+
+        if let Err(e) = f.call::<()>(args) {
+            warn!("Lua callback failed: {e}");
+        }
+
+        let dispatched = std::mem::take(&mut *self.lua.dispatched.borrow_mut());
+        for a in dispatched {
+            action_to_fn(a)(self);
+        }
+    }
+
+    /// Calls every function registered with `nwm.on` for `hook`. `arg` builds the table passed to
+    /// them. Hooks fired by actions a hook dispatched are skipped, a `focus` hook dispatching
+    /// `focus.left` would run itself forever otherwise
+    fn fire_hook(
+        &mut self,
+        hook: lua_cfg::Hook,
+        arg: impl FnOnce(&Self, &mlua::Lua) -> mlua::Result<mlua::Table>,
+    ) {
+        if self.in_hook {
+            info!("Not running the {hook:?} hook, it was fired from inside of a hook");
+            return;
+        }
+        let Some(lua) = self.lua.lua.clone() else {
+            return;
+        };
+        let Some(hooks) = self.lua.hooks.get(&hook).cloned() else {
+            return;
+        };
+
+        let arg = match arg(self, &lua) {
+            Ok(a) => a,
+            Err(e) => {
+                warn!("Failed to build the argument for the {hook:?} hook: {e}");
+                return;
+            }
+        };
+
+        self.in_hook = true;
+        for f in &hooks {
+            self.run_lua_callback(f, arg.clone());
+        }
+        self.in_hook = false;
+    }
+
+    /// Describes a window for lua hooks
+    fn window_lua_table(&self, lua: &mlua::Lua, id: WindowId) -> mlua::Result<mlua::Table> {
+        let t = lua.create_table()?;
+        t.set("id", id)?;
+        if let Some((instance, class)) = self.window_class(id) {
+            t.set("instance", instance)?;
+            t.set("class", class)?;
+        }
+        t.set("title", self.window_title(id))?;
+        t.set(
+            "floating",
+            self.workspaces
+                .iter()
+                .any(|ws| ws.floating.contains_key(&id)),
+        )?;
+        t.set("fullscreen", self.fullscreen.contains(&id))?;
+        t.set("workspace", self.workspace_of(id))?;
+        Ok(t)
+    }
+
+    fn workspace_of(&self, id: WindowId) -> Option<usize> {
+        self.workspaces
+            .iter()
+            .position(|ws| ws.windows().contains(&id) || ws.floating.contains_key(&id))
+    }
+
+    /// WM_CLASS of a window as (instance, class)
+    fn window_class(&self, w: WindowId) -> Option<(String, String)> {
+        let class = x11rb::properties::WmClass::get(&self.x11.conn, w)
+            .ok()?
+            .reply()
+            .ok()??;
+        Some((
+            String::from_utf8_lossy(class.instance()).into_owned(),
+            String::from_utf8_lossy(class.class()).into_owned(),
+        ))
+    }
+
+    /// _NET_WM_NAME of a window, falling back to WM_NAME for clients which don't set it
+    fn window_title(&self, w: WindowId) -> Option<String> {
+        let get = |property: Atom, ty: Atom| {
+            self.x11
+                .conn
+                .get_property(false, w, property, ty, 0, u32::MAX)
+                .ok()?
+                .reply()
+                .ok()
+                .filter(|r| !r.value.is_empty())
+                .map(|r| String::from_utf8_lossy(&r.value).into_owned())
+        };
+
+        self.net_wm_name_atom
+            .zip(self.utf8_string_atom)
+            .and_then(|(name, utf8)| get(name, utf8))
+            .or_else(|| get(AtomEnum::WM_NAME.into(), AtomEnum::ANY.into()))
+    }
+
+    /// Exposes a snapshot of the current state to lua as `nwm.state`
+    fn publish_lua_state(&self) -> mlua::Result<()> {
+        let Some(lua) = &self.lua.lua else {
+            return Ok(());
+        };
+        let ws = self.curr_ws();
+
+        let state = lua.create_table()?;
+        state.set("workspace", self.curr_workspace)?;
+        state.set("focused", ws.get_focused_id())?;
+        state.set("layout", ws.layout.to_string())?;
+        state.set("tiled", ws.windows().to_vec())?;
+        state.set("floating", ws.floating.keys().copied().collect::<Vec<_>>())?;
+
+        lua.globals().get::<mlua::Table>("nwm")?.set("state", state)
     }
 
     fn move_focused_to_ws(&mut self, ws: usize) {
         if let Some(id) = self.curr_ws().get_focused_id() {
-            if self.curr_ws_mut().is_floating(id) {
-                let g = self.curr_ws().get_geometry(id);
-                self.workspaces[ws].push_float_window(id, g);
-            } else {
-                self.workspaces[ws].push_window(id);
-            }
+            self.move_window_to_ws(id, ws);
+        }
+    }
+
+    /// Moves a managed window from whichever workspace it's on to `ws`, hiding or showing it when
+    /// it leaves or enters the current one
+    fn move_window_to_ws(&mut self, id: WindowId, ws: usize) {
+        let Some(from) = self.workspace_of(id) else {
+            return;
+        };
+        if ws >= self.workspaces.len() || ws == from {
+            return;
+        }
+
+        if self.workspaces[from].is_floating(id) {
+            let g = self.workspaces[from].get_geometry(id);
+            self.workspaces[ws].push_float_window(id, g);
+        } else {
+            self.workspaces[ws].push_window(id);
+        }
+        if from == self.curr_workspace {
             self.x11.unmap_window(id).unwrap();
-            self.curr_ws_mut().remove_window(id);
+        }
+        self.workspaces[from].remove_window(id);
+        self.set_window_desktop(id, ws);
+        self.sync_active_window();
+
+        if ws == self.curr_workspace {
+            self.x11.map_window(id).unwrap();
+            self.layout();
         }
     }
 
     fn reload_config(&mut self) {
-        let conf = match lua_cfg::load_config(&self.config_path, true) {
+        let mut conf = match lua_cfg::load_config(&self.config_path, true) {
             Ok(c) => c,
             Err(e) => {
-                warn!("Failed to reload lua config: {e:?}");
+                warn!("Failed to reload lua config, keeping the old one: {e}");
+                if self.settings.reload_notice {
+                    _ = Command::new("notify-send")
+                        .args(["-u", "critical", "nwm: failed to reload the config"])
+                        .arg(e.to_string())
+                        .spawn()
+                        .map_err(|e| warn!("Failed to run notify-send: {e}"));
+                }
                 return;
             }
         };
 
         self.binds.clear();
 
-        let (gap, binds, terminal, launcher, active, inactive, width) =
-            Self::apply_lua_config(conf, &mut self.x11);
+        let lua = std::mem::take(&mut conf.runtime);
+        let (binds, settings) = Self::apply_lua_config(conf, &mut self.x11);
 
-        self.gap = gap;
         self.binds = binds;
-        self.terminal = terminal;
-        self.launcher = launcher;
-        self.active_border_color = active;
-        self.inactive_border_color = inactive;
-        self.border_width = width;
+        let old = std::mem::replace(&mut self.settings, settings);
+        self.lua = lua;
+        self.apply_workspace_layouts(Some(&old));
+        self.publish_desktops();
 
         for ws in self.workspaces.clone() {
-            for w in ws.windows() {
-                self.set_window_border_width(*w, self.border_width);
-            }
-            for w in ws.floating.keys() {
-                self.set_window_border_width(*w, self.border_width);
+            for w in ws.windows().iter().chain(ws.floating.keys()) {
+                if !self.fullscreen.contains(w) {
+                    self.set_window_border_width(*w, self.settings.border_width as u8);
+                }
             }
         }
+        self.layout();
+        self.update_config_watch();
 
         info!("Reloaded lua config");
+
+        self.fire_hook(lua_cfg::Hook::Reload, |nwm, lua| {
+            let t = lua.create_table()?;
+            t.set("config", nwm.config_path.display().to_string())?;
+            Ok(t)
+        });
+        self.emit_event(
+            ipc::EventKind::Reload,
+            |nwm| serde_json::json!({ "config": nwm.config_path }),
+        );
     }
 
-    pub fn create(display_name: &str) -> Option<Self> {
-        let file = std::fs::OpenOptions::new()
-            .append(true)
-            .create(true)
-            .open("/tmp/nwm.log")
-            .unwrap();
+    /// Starts or stops watching the config, following `settings.auto_reload`
+    fn update_config_watch(&mut self) {
+        if !self.settings.auto_reload {
+            self.config_watch = None;
+            self.reload_at = None;
+        } else if self.config_watch.is_none() {
+            self.config_watch = config_watch::ConfigWatch::new(&self.config_path);
+        }
+    }
 
-        multi_log::MultiLog::init(
-            vec![
-                Box::new(env_logger::Logger::from_default_env()),
-                Box::new(nw_log_connection::NwLogLog::init(file)),
-            ],
-            log::Level::Trace,
-        );
-        let mut x11_ab = better_x11rb::X11RB::init()?;
+    /// Reloads the config once it stopped changing for `RELOAD_DEBOUNCE`
+    fn check_config_watch(&mut self) {
+        if self.config_watch.as_mut().is_some_and(|w| w.changed()) {
+            self.reload_at = Some(Instant::now() + RELOAD_DEBOUNCE);
+        }
+        if self.reload_at.is_some_and(|at| at <= Instant::now()) {
+            self.reload_at = None;
+            info!("The config changed on disk, reloading it");
+            self.reload_config();
+        }
+    }
+
+    pub fn create(args: &cli::Args, display_name: &str) -> Option<Self> {
+        let mut x11_ab = better_x11rb::X11RB::init(display_name, args.replace)?;
 
         info!("Succesfully initialized display {} ", display_name);
 
-        let dirs = platform_dirs::AppDirs::new(Some("nwm"), false).unwrap();
-        _ = std::fs::create_dir(&dirs.config_dir);
-        let mut conf_dir = dirs.config_dir.clone();
-        conf_dir.push("config.lua");
+        let conf_dir = args.config.clone().unwrap_or_else(default_config_path);
 
-        let conf = lua_cfg::load_config(&conf_dir, false).unwrap_or_else(|_| {
-            warn!("Failed to load config on startup using barebones default config");
+        let mut conf = lua_cfg::load_config(&conf_dir, false).unwrap_or_else(|e| {
+            warn!("Failed to load config on startup ({e}), using barebones default config");
             lua_cfg::Config::default()
         });
-        let (gap, binds, terminal, launcher, active, inactive, width) =
-            Self::apply_lua_config(conf, &mut x11_ab);
+        let lua = std::mem::take(&mut conf.runtime);
+        let (binds, settings) = Self::apply_lua_config(conf, &mut x11_ab);
 
         info!("Everything went well in initialization :DD");
-        if launcher.is_empty() {
+        if settings.launcher.is_empty() {
             warn!("Launcher wasn't set to a program");
         }
-        if terminal.is_empty() {
+        if settings.terminal.is_empty() {
             warn!("Terminal wasn't set to a program");
         }
 
@@ -500,55 +771,150 @@ impl Nwm {
                 "Failed to intern _NET_WM_STRUT_PARTIAL, docks that depend on this won't resize other windows"
             );
         }
-        use x11rb::wrapper::ConnectionExt;
-
+        let net_wm_name_atom = x11_ab.intern_atom(b"_NET_WM_NAME");
+        let utf8_string_atom = x11_ab.intern_atom(b"UTF8_STRING");
+        if net_wm_name_atom.is_none() || utf8_string_atom.is_none() {
+            warn!(
+                "Failed to intern _NET_WM_NAME or UTF8_STRING, window titles will fall back to WM_NAME"
+            );
+        }
+        let wm_protocols_atom = x11_ab.intern_atom(b"WM_PROTOCOLS");
+        let wm_delete_window_atom = x11_ab.intern_atom(b"WM_DELETE_WINDOW");
+        if wm_protocols_atom.is_none() || wm_delete_window_atom.is_none() {
+            warn!(
+                "Failed to intern WM_PROTOCOLS or WM_DELETE_WINDOW, closing windows will destroy them"
+            );
+        }
+        let net_wm_ping_atom = x11_ab.intern_atom(b"_NET_WM_PING");
+        let net_wm_pid_atom = x11_ab.intern_atom(b"_NET_WM_PID");
+        if net_wm_ping_atom.is_none() || net_wm_pid_atom.is_none() {
+            warn!("Failed to intern _NET_WM_PING or _NET_WM_PID, hung windows won't be detected");
+        }
+        let net_wm_desktop_atom = x11_ab.intern_atom(b"_NET_WM_DESKTOP");
+        if net_wm_desktop_atom.is_none() {
+            warn!(
+                "Failed to intern _NET_WM_DESKTOP, windows adopted on startup will go to the first workspace"
+            );
+        }
+        let wm_state_atom = x11_ab.intern_atom(b"WM_STATE");
+        if wm_state_atom.is_none() {
+            warn!("Failed to intern WM_STATE, hidden windows won't be adopted on startup");
+        }
+        let net_client_list_atom = x11_ab.intern_atom(b"_NET_CLIENT_LIST");
+        let net_client_list_stacking_atom = x11_ab.intern_atom(b"_NET_CLIENT_LIST_STACKING");
+        if net_client_list_atom.is_none() || net_client_list_stacking_atom.is_none() {
+            warn!(
+                "Failed to intern _NET_CLIENT_LIST or _NET_CLIENT_LIST_STACKING, pagers won't see the windows"
+            );
+        }
+        let net_supported_atom = x11_ab.intern_atom(b"_NET_SUPPORTED");
+        let net_supporting_wm_check_atom = x11_ab.intern_atom(b"_NET_SUPPORTING_WM_CHECK");
+        if net_supported_atom.is_none() || net_supporting_wm_check_atom.is_none() {
+            warn!(
+                "Failed to intern _NET_SUPPORTED or _NET_SUPPORTING_WM_CHECK, other programs won't know an EWMH window manager is running"
+            );
+        }
         let active_desktop_atom = x11_ab.intern_atom(b"_NET_CURRENT_DESKTOP");
-        if let Some(at) = active_desktop_atom {
-            _ = x11_ab
-                .conn
-                .change_property32(
-                    PropMode::REPLACE,
-                    x11_ab.root_window(),
-                    at,
-                    AtomEnum::CARDINAL,
-                    &[10],
-                )
-                .map_err(|e| {
-                    warn!("Failed to set _NET_CURRENT_DESKTOP: {e}");
-                });
+        let net_active_window_atom = x11_ab.intern_atom(b"_NET_ACTIVE_WINDOW");
+        let net_close_window_atom = x11_ab.intern_atom(b"_NET_CLOSE_WINDOW");
+        if net_active_window_atom.is_none() || net_close_window_atom.is_none() {
+            warn!(
+                "Failed to intern _NET_ACTIVE_WINDOW or _NET_CLOSE_WINDOW, pagers won't be able to focus or close windows"
+            );
+        }
+        let net_wm_state_atom = x11_ab.intern_atom(b"_NET_WM_STATE");
+        let net_wm_state_focused_atom = x11_ab.intern_atom(b"_NET_WM_STATE_FOCUSED");
+        if net_wm_state_atom.is_none() || net_wm_state_focused_atom.is_none() {
+            warn!(
+                "Failed to intern _NET_WM_STATE or _NET_WM_STATE_FOCUSED, windows won't know they are focused"
+            );
+        }
+        let net_wm_state_fullscreen_atom = x11_ab.intern_atom(b"_NET_WM_STATE_FULLSCREEN");
+        if net_wm_state_fullscreen_atom.is_none() {
+            warn!("Failed to intern _NET_WM_STATE_FULLSCREEN, windows can't ask to be fullscreen");
+        }
+        let net_number_of_desktops_atom = x11_ab.intern_atom(b"_NET_NUMBER_OF_DESKTOPS");
+        let net_desktop_names_atom = x11_ab.intern_atom(b"_NET_DESKTOP_NAMES");
+        let net_desktop_viewport_atom = x11_ab.intern_atom(b"_NET_DESKTOP_VIEWPORT");
+        if net_number_of_desktops_atom.is_none()
+            || net_desktop_names_atom.is_none()
+            || net_desktop_viewport_atom.is_none()
+        {
+            warn!(
+                "Failed to intern the _NET_*DESKTOP* atoms, pagers won't be able to show the workspaces"
+            );
         }
 
-        Some(Self {
+        let mut nwm = Self {
             x11: x11_ab,
             workspaces: Default::default(),
             curr_workspace: 0,
-            gap,
             running: true,
             last_x: 0,
             last_y: 0,
             binds,
-            launcher,
-            terminal,
+            settings,
+            lua,
             window_type_atom,
             window_type_dock_atom,
             strut_partial_atom,
+            net_wm_name_atom,
+            utf8_string_atom,
             active_desktop_atom,
             window_type_normal_atom,
+            wm_protocols_atom,
+            wm_delete_window_atom,
+            net_wm_ping_atom,
+            net_wm_pid_atom,
+            net_wm_desktop_atom,
+            wm_state_atom,
+            net_client_list_atom,
+            net_client_list_stacking_atom,
+            net_supported_atom,
+            net_supporting_wm_check_atom,
+            net_number_of_desktops_atom,
+            net_desktop_names_atom,
+            net_desktop_viewport_atom,
+            net_active_window_atom,
+            net_close_window_atom,
+            net_wm_state_atom,
+            net_wm_state_focused_atom,
+            net_wm_state_fullscreen_atom,
+            fullscreen: HashSet::new(),
+            active_window: None,
+            clients: Vec::new(),
+            stacking: Vec::new(),
+            pings: HashMap::new(),
+            unresponsive: HashSet::new(),
             struts: HashMap::new(),
+            tiled_rects: Vec::new(),
             last_focused: None,
-            active_border_color: active,
-            inactive_border_color: inactive,
-            border_width: width,
             config_path: conf_dir,
             suppress_cursor_focus: false,
-        })
+            in_hook: false,
+            restart_pending: false,
+            ipc: ipc::IpcServer::bind(ipc::socket_path(display_name)),
+            display_name: display_name.to_string(),
+            config_watch: None,
+            reload_at: None,
+        };
+        nwm.apply_workspace_layouts(None);
+        nwm.update_config_watch();
+        if let Some(s) = session::Session::take(&session::state_path(display_name)) {
+            nwm.restore_session(s);
+        }
+        nwm.adopt_windows();
+        nwm.publish_ewmh_support();
+        nwm.publish_desktops();
+        nwm.update_current_desktop();
+        nwm.update_client_list();
+        // Whatever the previous window manager left in there
+        nwm.publish_active_window();
+
+        Some(nwm)
     }
     fn refocus_and_warp(&mut self, id: WindowId) {
-        if let Some((_, r)) = self
-            .tiled_window_rects()
-            .into_iter()
-            .find(|(w, _)| *w == id)
-        {
+        if let Some((_, r)) = self.laid_out_rects().into_iter().find(|(w, _)| *w == id) {
             let cx = r.x + r.w / 2;
             let cy = r.y + r.h / 2;
             self.x11
@@ -633,6 +999,31 @@ impl Nwm {
         self.switch_ws((self.curr_workspace - 1).clamp(0, 10));
     }
 
+    fn next_layout(&mut self) {
+        let l = self.curr_ws().layout.next(&self.settings.custom_layouts);
+        self.set_layout(l);
+    }
+
+    fn prev_layout(&mut self) {
+        let l = self.curr_ws().layout.prev(&self.settings.custom_layouts);
+        self.set_layout(l);
+    }
+
+    fn set_layout(&mut self, layout: Layout) {
+        info!(
+            "Workspace {} is now using the {layout} layout",
+            self.curr_workspace
+        );
+        self.curr_ws_mut().layout = layout;
+        self.layout();
+        self.emit_event(ipc::EventKind::Layout, |nwm| {
+            serde_json::json!({
+                "workspace": nwm.curr_workspace,
+                "layout": nwm.curr_ws().layout.to_string(),
+            })
+        });
+    }
+
     fn focused(&self) -> Option<WindowId> {
         self.workspaces[self.curr_workspace].focused
     }
@@ -643,206 +1034,988 @@ impl Nwm {
         }
     }
 
+    /// Asks the window to close itself with `WM_DELETE_WINDOW` so it can e.g. prompt to save,
+    /// windows that don't support it get destroyed. Windows supporting `_NET_WM_PING` get pinged
+    /// as well, if they don't answer in time the next close kills them
     fn close_window(&mut self, id: WindowId) {
-        self.x11.close_window(id).unwrap();
-        // self.curr_ws_mut().remove_window(id);
-    }
-
-    fn curr_ws_mut(&mut self) -> &mut Workspace {
-        &mut self.workspaces[self.curr_workspace]
-    }
-    fn curr_ws(&self) -> &Workspace {
-        &self.workspaces[self.curr_workspace]
-    }
-
-    pub fn run(mut self) {
-        info!("Keybindings were setup");
-
-        while self.running {
-            let event = self.x11.next_event().unwrap();
-
-            match event {
-                Event::MapRequest(e) => self.add_window(e),
-                Event::UnmapNotify(e) => self.remove_window(e),
-                Event::KeyPress(e) => {
-                    for b in &self.binds.clone() {
-                        b.try_do(&mut self, e);
-                    }
-                }
-                Event::MotionNotify(_) => {
-                    if self.suppress_cursor_focus {
-                        continue;
-                    }
-                    let (x, y) = self.x11.mouse_pos();
-                    if self.last_x != x || self.last_y != y {
-                        let rects = self.tiled_window_rects();
-                        for (i, r) in rects.iter() {
-                            if x > r.x && x < r.x + r.w {
-                                self.curr_ws_mut().set_focused_id(*i);
-                                self.set_focus(*i);
-                            }
-                        }
-                        self.last_x = x;
-                        self.last_y = y;
-                    }
-                }
-                Event::EnterNotify(e) => {
-                    self.curr_ws_mut().set_focused_id(e.event);
-                    self.set_focus(e.event);
-                }
-                Event::KeyRelease(_) => {}
-                Event::MappingNotify(_) => {}
-                Event::ConfigureRequest(_) => self.layout(),
-                Event::PropertyNotify(e) => {
-                    if self.strut_partial_atom.is_none() {
-                        continue;
-                    }
-                    let spa = self.strut_partial_atom.unwrap();
-                    if e.atom == spa
-                        && let Some(strut) = self.get_strut_partial(e.window, spa)
-                    {
-                        self.struts.insert(e.window, Strut::from(strut));
-                        self.layout();
-                    }
-                }
-                Event::DestroyNotify(e) => {
-                    self.struts.remove(&e.window);
-                    self.layout();
-                }
-
-                Event::CreateNotify(_) | Event::MapNotify(_) | Event::ConfigureNotify(_) => {}
-                _ => {
-                    warn!("Skipping event: {:#?}", event);
-                }
-            }
+        if self.unresponsive.contains(&id) {
+            warn!("Killing unresponsive window {id}");
+            self.force_kill(id);
+            return;
         }
-    }
 
-    fn focus_on_pointer(&mut self) {
-        let rects = self.floating_window_rects();
+        let Some(protocols) = self.wm_protocols_atom else {
+            self.x11.close_window(id);
+            return;
+        };
+        let supported = self.window_protocols(id);
+        let Some(delete) = self.wm_delete_window_atom.filter(|d| supported.contains(d)) else {
+            self.x11.close_window(id);
+            return;
+        };
 
-        for (id, r) in rects.iter() {
-            if self.last_x > r.x
-                && self.last_x < r.x + r.w
-                && self.last_y > r.y
-                && self.last_y < r.y + r.h
-            {
-                self.set_window_border_pixel(*id, self.inactive_border_color);
-                self.curr_ws_mut().set_focused_id(*id);
-                self.set_focus(*id);
-                return;
-            }
+        self.x11.send_protocol_message(id, protocols, delete);
+        if let Some(ping) = self.net_wm_ping_atom.filter(|p| supported.contains(p))
+            && !self.pings.contains_key(&id)
+        {
+            self.x11.send_ping(id, protocols, ping);
+            self.pings.insert(id, Instant::now() + PING_TIMEOUT);
         }
+    }
 
-        let rects = self.tiled_window_rects();
-        for (i, r) in rects.iter() {
-            if self.last_x > r.x && self.last_x < r.x + r.w {
-                self.set_window_border_pixel(*i, self.inactive_border_color);
-                self.curr_ws_mut().set_focused_id(*i);
-                self.set_focus(*i);
-                return;
+    /// Kills the process behind `_NET_WM_PID` (only if it runs on this machine) and disconnects
+    /// the client from the x server
+    fn force_kill(&mut self, id: WindowId) {
+        if let Some(pid) = self.local_window_pid(id) {
+            match kill(Pid::from_raw(pid as i32), Signal::SIGKILL) {
+                Ok(()) => info!("Sent SIGKILL to process {pid} (window {id})"),
+                Err(e) => warn!("Failed to kill process {pid} (window {id}): {e}"),
             }
         }
+        self.x11.kill_client(id);
+        self.forget_window(id);
     }
 
-    fn set_window_border_pixel(&mut self, w: WindowId, color: u32) {
-        _ = self
+    /// `_NET_WM_PID` of a window, `None` if it's missing or the client runs on another host
+    fn local_window_pid(&self, id: WindowId) -> Option<u32> {
+        let pid = self
             .x11
             .conn
-            .change_window_attributes(w, &ChangeWindowAttributesAux::new().border_pixel(color));
-    }
+            .get_property(false, id, self.net_wm_pid_atom?, AtomEnum::CARDINAL, 0, 1)
+            .ok()?
+            .reply()
+            .ok()?
+            .value32()?
+            .next()?;
 
-    fn set_window_border_width(&mut self, w: WindowId, width: u8) {
-        self.x11
+        let machine = self
+            .x11
             .conn
-            .configure_window(w, &ConfigureWindowAux::new().border_width(width as u32))
-            .unwrap();
+            .get_property(
+                false,
+                id,
+                AtomEnum::WM_CLIENT_MACHINE,
+                AtomEnum::STRING,
+                0,
+                u32::MAX,
+            )
+            .ok()?
+            .reply()
+            .ok()?
+            .value;
+        let hostname = nix::unistd::gethostname().ok()?;
+        (machine == hostname.as_encoded_bytes()).then_some(pid)
     }
 
-    fn switch_ws(&mut self, new_ws: usize) {
-        if new_ws >= self.workspaces.len() || new_ws == self.curr_workspace {
-            return;
+    /// Marks every window whose ping timed out as unresponsive
+    fn check_pings(&mut self) {
+        let now = Instant::now();
+        let expired = self
+            .pings
+            .iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+
+        for id in expired {
+            self.pings.remove(&id);
+            warn!(
+                "Window {id} didn't answer _NET_WM_PING within {}s, closing it again will kill it",
+                PING_TIMEOUT.as_secs()
+            );
+            self.unresponsive.insert(id);
+            self.set_window_border_pixel(id, self.settings.border_unresponsive_color);
         }
+    }
 
-        let old_ws = self.curr_workspace;
-
-        for &w in self.workspaces[old_ws].windows() {
-            self.x11.unmap_window(w).unwrap();
+    fn handle_pong(&mut self, id: WindowId) {
+        self.pings.remove(&id);
+        if self.unresponsive.remove(&id) {
+            info!("Window {id} is responding again");
+            let color = if self.last_focused == Some(id) {
+                self.settings.border_active_color
+            } else {
+                self.settings.border_inactive_color
+            };
+            self.set_window_border_pixel(id, color);
         }
+    }
 
-        for w in self.workspaces[old_ws].floating.keys() {
-            self.x11.unmap_window(*w).unwrap();
+    /// Drops the bookkeeping about a window that is gone
+    fn forget_window(&mut self, id: WindowId) {
+        self.pings.remove(&id);
+        self.unresponsive.remove(&id);
+        // Windows on other workspaces get destroyed without being unmapped by their client first,
+        // killed ones are forgotten before their unmap arrives
+        self.unmanage(id);
+    }
+
+    /// Stops managing a window that was closed, withdrawn or killed and tells hooks and ipc
+    /// subscribers about it. Does nothing for windows that aren't managed (anymore)
+    fn unmanage(&mut self, id: WindowId) {
+        if self.workspace_of(id).is_none() {
+            return;
         }
+        // The window is still on its workspace, so hooks and subscribers get its full info
+        self.fire_hook(lua_cfg::Hook::WindowClose, |nwm, lua| {
+            nwm.window_lua_table(lua, id)
+        });
+        self.emit_event(
+            ipc::EventKind::WindowClose,
+            |nwm| serde_json::json!({ "window": nwm.window_json(id) }),
+        );
 
-        self.curr_workspace = new_ws;
+        self.fullscreen.remove(&id);
+        // A hook might have moved it to another workspace
+        if let Some(ws) = self.workspace_of(id) {
+            self.workspaces[ws].remove_window(id);
+        }
+        self.update_client_list();
+        self.sync_active_window();
+    }
 
-        for &w in self.workspaces[new_ws].windows() {
-            self.x11.map_window(w).unwrap();
+    /// Publishes the focused window in `_NET_ACTIVE_WINDOW` and moves `_NET_WM_STATE_FOCUSED` to
+    /// it. None once the focused window left the current workspace (or it's empty)
+    fn sync_active_window(&mut self) {
+        let active = self
+            .last_focused
+            .filter(|w| self.workspace_of(*w) == Some(self.curr_workspace));
+        if active == self.active_window {
+            return;
         }
 
-        for w in self.workspaces[new_ws].floating.keys() {
-            self.x11.map_window(*w).unwrap();
+        if let Some(focused) = self.net_wm_state_focused_atom {
+            // A window that is gone can't have its state changed anymore
+            if let Some(old) = self
+                .active_window
+                .filter(|w| self.workspace_of(*w).is_some())
+            {
+                self.set_window_state(old, focused, false);
+            }
+            if let Some(new) = active {
+                self.set_window_state(new, focused, true);
+            }
         }
+        self.active_window = active;
+        self.publish_active_window();
+    }
 
-        if let Some(ada) = self.active_desktop_atom {
-            self.x11
+    fn publish_active_window(&mut self) {
+        if let Some(atom) = self.net_active_window_atom {
+            _ = self
+                .x11
                 .conn
                 .change_property32(
                     PropMode::REPLACE,
                     self.x11.root_window(),
-                    ada,
-                    AtomEnum::CARDINAL,
-                    &[(new_ws) as u32],
+                    atom,
+                    AtomEnum::WINDOW,
+                    &[self.active_window.unwrap_or(x11rb::NONE)],
                 )
-                .unwrap();
+                .map_err(|e| warn!("Failed to set _NET_ACTIVE_WINDOW: {e}"));
         }
-
-        self.layout();
-        self.focus_on_pointer();
     }
 
-    fn tiled_window_rects(&self) -> Vec<(WindowId, Rect)> {
-        if self.curr_ws().empty() {
+    /// `_NET_WM_STATE` of a window, the atoms of every state it's in
+    fn window_state(&self, w: WindowId) -> Vec<Atom> {
+        let Some(state) = self.net_wm_state_atom else {
             return vec![];
-        }
-
-        let mut rs = vec![];
-        let (mut sw, mut sh) = self.x11.screen_size();
-
-        let reserved = self.get_reserved_space();
+        };
+        self.x11
+            .conn
+            .get_property(false, w, state, AtomEnum::ATOM, 0, 32)
+            .ok()
+            .and_then(|c| c.reply().ok())
+            .and_then(|r| r.value32().map(|v| v.collect()))
+            .unwrap_or_default()
+    }
+
+    /// Adds or removes one state in `_NET_WM_STATE`, keeping the others
+    fn set_window_state(&mut self, w: WindowId, flag: Atom, on: bool) {
+        let Some(state) = self.net_wm_state_atom else {
+            return;
+        };
+        let mut states = self.window_state(w);
+        if states.contains(&flag) == on {
+            return;
+        }
+        if on {
+            states.push(flag);
+        } else {
+            states.retain(|s| *s != flag);
+        }
+        _ = self
+            .x11
+            .conn
+            .change_property32(PropMode::REPLACE, w, state, AtomEnum::ATOM, &states)
+            .map_err(|e| warn!("Failed to set _NET_WM_STATE of {w}: {e}"));
+    }
+
+    /// Sets up the `_NET_SUPPORTING_WM_CHECK` window and advertises what nwm supports in
+    /// `_NET_SUPPORTED`, so pagers and tools like wmctrl know an EWMH window manager is running
+    fn publish_ewmh_support(&mut self) {
+        let root = self.x11.root_window();
+        let check = self.x11.wm_window();
+
+        if let Some(check_atom) = self.net_supporting_wm_check_atom {
+            for w in [root, check] {
+                _ = self
+                    .x11
+                    .conn
+                    .change_property32(PropMode::REPLACE, w, check_atom, AtomEnum::WINDOW, &[check])
+                    .map_err(|e| warn!("Failed to set _NET_SUPPORTING_WM_CHECK: {e}"));
+            }
+        }
+        if let (Some(name), Some(utf8)) = (self.net_wm_name_atom, self.utf8_string_atom) {
+            _ = self
+                .x11
+                .conn
+                .change_property8(PropMode::REPLACE, check, name, utf8, b"nwm")
+                .map_err(|e| warn!("Failed to set _NET_WM_NAME of the check window: {e}"));
+        }
+
+        let Some(supported_atom) = self.net_supported_atom else {
+            return;
+        };
+        let supported = [
+            Some(supported_atom),
+            self.net_supporting_wm_check_atom,
+            self.net_client_list_atom,
+            self.net_client_list_stacking_atom,
+            self.active_desktop_atom,
+            self.net_number_of_desktops_atom,
+            self.net_desktop_names_atom,
+            self.net_desktop_viewport_atom,
+            self.net_active_window_atom,
+            self.net_close_window_atom,
+            self.net_wm_state_atom,
+            self.net_wm_state_focused_atom,
+            self.net_wm_state_fullscreen_atom,
+            self.net_wm_name_atom,
+            self.net_wm_desktop_atom,
+            self.window_type_atom,
+            self.window_type_normal_atom,
+            self.window_type_dock_atom,
+            self.strut_partial_atom,
+            self.net_wm_ping_atom,
+            self.net_wm_pid_atom,
+        ]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+        _ = self
+            .x11
+            .conn
+            .change_property32(
+                PropMode::REPLACE,
+                root,
+                supported_atom,
+                AtomEnum::ATOM,
+                &supported,
+            )
+            .map_err(|e| warn!("Failed to set _NET_SUPPORTED: {e}"));
+    }
+
+    /// Publishes the amount of workspaces, their names and (always zero, there's no large desktop)
+    /// viewports
+    fn publish_desktops(&mut self) {
+        let root = self.x11.root_window();
+        let count = self.workspaces.len();
+
+        if let Some(atom) = self.net_number_of_desktops_atom {
+            _ = self
+                .x11
+                .conn
+                .change_property32(
+                    PropMode::REPLACE,
+                    root,
+                    atom,
+                    AtomEnum::CARDINAL,
+                    &[count as u32],
+                )
+                .map_err(|e| warn!("Failed to set _NET_NUMBER_OF_DESKTOPS: {e}"));
+        }
+        if let Some(atom) = self.net_desktop_viewport_atom {
+            _ = self
+                .x11
+                .conn
+                .change_property32(
+                    PropMode::REPLACE,
+                    root,
+                    atom,
+                    AtomEnum::CARDINAL,
+                    &vec![0; count * 2],
+                )
+                .map_err(|e| warn!("Failed to set _NET_DESKTOP_VIEWPORT: {e}"));
+        }
+        if let (Some(atom), Some(utf8)) = (self.net_desktop_names_atom, self.utf8_string_atom) {
+            // Every name is null terminated
+            let names = (0..count)
+                .flat_map(|ws| {
+                    let mut name = self.settings.workspace_name(ws).into_bytes();
+                    name.push(0);
+                    name
+                })
+                .collect::<Vec<_>>();
+            _ = self
+                .x11
+                .conn
+                .change_property8(PropMode::REPLACE, root, atom, utf8, &names)
+                .map_err(|e| warn!("Failed to set _NET_DESKTOP_NAMES: {e}"));
+        }
+    }
+
+    /// Requests pagers, bars and tools like rofi or wmctrl send to the root window
+    fn handle_ewmh_message(&mut self, e: ClientMessageEvent) {
+        let data = e.data.as_data32();
+        let managed = self.workspace_of(e.window);
+
+        if Some(e.type_) == self.active_desktop_atom {
+            self.switch_ws(data[0] as usize);
+        } else if Some(e.type_) == self.net_active_window_atom
+            && let Some(ws) = managed
+        {
+            self.switch_ws(ws);
+            if self.curr_ws_mut().is_floating(e.window) {
+                self.raise(e.window);
+            }
+            self.set_focus(e.window);
+        } else if Some(e.type_) == self.net_close_window_atom && managed.is_some() {
+            self.close_window(e.window);
+        } else if Some(e.type_) == self.net_wm_state_atom
+            && managed.is_some()
+            && let Some(fs) = self.net_wm_state_fullscreen_atom
+            && (data[1] == fs || data[2] == fs)
+        {
+            // 0 removes, 1 adds and 2 toggles the state
+            let on = match data[0] {
+                0 => false,
+                1 => true,
+                _ => !self.fullscreen.contains(&e.window),
+            };
+            self.set_fullscreen(e.window, on);
+        } else if Some(e.type_) == self.net_wm_desktop_atom && managed.is_some() {
+            // 0xffffffff (every desktop) isn't supported, it's ignored as an out of range workspace
+            self.move_window_to_ws(e.window, data[0] as usize);
+        } else {
+            info!("Ignoring client message {} for {}", e.type_, e.window);
+        }
+    }
+
+    /// Sets `_NET_WM_DESKTOP` of a window to the workspace it's on
+    fn set_window_desktop(&mut self, window: WindowId, ws: usize) {
+        if let Some(atom) = self.net_wm_desktop_atom {
+            _ = self
+                .x11
+                .conn
+                .change_property32(
+                    PropMode::REPLACE,
+                    window,
+                    atom,
+                    AtomEnum::CARDINAL,
+                    &[ws as u32],
+                )
+                .map_err(|e| warn!("Failed to set _NET_WM_DESKTOP of {window}: {e}"));
+        }
+    }
 
-        let offset = (reserved.x0, reserved.y0);
+    fn update_current_desktop(&mut self) {
+        if let Some(ada) = self.active_desktop_atom {
+            _ = self
+                .x11
+                .conn
+                .change_property32(
+                    PropMode::REPLACE,
+                    self.x11.root_window(),
+                    ada,
+                    AtomEnum::CARDINAL,
+                    &[self.curr_workspace as u32],
+                )
+                .map_err(|e| warn!("Failed to set _NET_CURRENT_DESKTOP: {e}"));
+        }
+    }
 
-        sw -= (reserved.x0 + reserved.x1) as u16;
-        sh -= (reserved.y0 + reserved.y1) as u16;
+    /// Publishes the managed windows in `_NET_CLIENT_LIST` (mapping order) and
+    /// `_NET_CLIENT_LIST_STACKING`. New windows are mapped on top of the others, so they go to the
+    /// top of the stacking order
+    fn update_client_list(&mut self) {
+        self.clients.retain(|w| {
+            self.workspaces
+                .iter()
+                .any(|ws| ws.windows().contains(w) || ws.floating.contains_key(w))
+        });
+        self.stacking.retain(|w| self.clients.contains(w));
+        for w in &self.clients {
+            if !self.stacking.contains(w) {
+                self.stacking.push(*w);
+            }
+        }
 
-        let n = (self.curr_ws().window_count()) as i16;
-        if n == 0 {
-            return rs;
+        if let Some(atom) = self.net_client_list_atom {
+            _ = self
+                .x11
+                .conn
+                .change_property32(
+                    PropMode::REPLACE,
+                    self.x11.root_window(),
+                    atom,
+                    AtomEnum::WINDOW,
+                    &self.clients,
+                )
+                .map_err(|e| warn!("Failed to update the client list: {e}"));
         }
+        self.publish_stacking();
+    }
 
-        let gap = self.gap as i16;
-        let half_gap = gap / 2;
+    fn publish_stacking(&mut self) {
+        let Some(atom) = self.net_client_list_stacking_atom else {
+            return;
+        };
+        _ = self
+            .x11
+            .conn
+            .change_property32(
+                PropMode::REPLACE,
+                self.x11.root_window(),
+                atom,
+                AtomEnum::WINDOW,
+                &self.stacking,
+            )
+            .map_err(|e| warn!("Failed to update the stacking client list: {e}"));
+    }
+
+    /// Raises a window to the top and keeps `_NET_CLIENT_LIST_STACKING` in that order
+    fn raise(&mut self, w: WindowId) {
+        self.x11.raise_window(w);
+        if self.stacking.contains(&w) && self.stacking.last() != Some(&w) {
+            self.stacking.retain(|s| *s != w);
+            self.stacking.push(w);
+            self.publish_stacking();
+        }
+    }
+
+    fn kill_focused(&mut self) {
+        if let Some(w) = self.focused() {
+            self.x11.kill_client(w);
+        }
+    }
 
-        let usable_w = sw as i16 - gap * 2;
-        let slot_w = usable_w / n;
+    /// The ICCCM `WM_PROTOCOLS` a window takes part in
+    fn window_protocols(&self, w: WindowId) -> Vec<Atom> {
+        let Some(protocols) = self.wm_protocols_atom else {
+            return vec![];
+        };
+        self.x11
+            .conn
+            .get_property(false, w, protocols, AtomEnum::ATOM, 0, 32)
+            .ok()
+            .and_then(|c| c.reply().ok())
+            .and_then(|r| Some(r.value32()?.collect()))
+            .unwrap_or_default()
+    }
 
-        for i in 0..n {
-            let x = gap + i * slot_w + half_gap + offset.0 as i16;
-            let y = gap + offset.1 as i16;
+    fn curr_ws_mut(&mut self) -> &mut Workspace {
+        &mut self.workspaces[self.curr_workspace]
+    }
+    fn curr_ws(&self) -> &Workspace {
+        &self.workspaces[self.curr_workspace]
+    }
 
-            let w = slot_w - half_gap * 2;
-            let h = sh as i16 - gap * 2;
+    /// Runs until nwm is asked to quit (success) or the connection to the X server breaks
+    pub fn run(mut self) -> ExitCode {
+        info!("Keybindings were setup");
 
-            if w > 0 && h > 0 {
-                rs.push((
-                    *self.curr_ws().get_tiled_window_id(i as usize).unwrap(),
-                    Rect { x, y, w, h },
-                ));
+        let mut exit = ExitCode::SUCCESS;
+        while self.running {
+            self.check_pings();
+            self.check_config_watch();
+            self.handle_ipc();
+            loop {
+                match self.x11.poll_event() {
+                    Ok(Some(event)) => self.handle_event(event),
+                    Ok(None) => break,
+                    Err(e) => {
+                        error!("Lost the connection to the X server: {e}");
+                        self.running = false;
+                        exit = ExitCode::FAILURE;
+                        break;
+                    }
+                }
+            }
+            if self.running && std::mem::take(&mut self.restart_pending) {
+                self.restart();
+            }
+            if !self.running {
+                break;
             }
+            self.x11.flush();
+            if !self.wait_for_input() {
+                error!("The connection to the X server hung up");
+                exit = ExitCode::FAILURE;
+                break;
+            }
+        }
+
+        // Before the x11 connection goes away, a window manager replacing nwm might bind the
+        // socket right after that
+        self.ipc = None;
+        exit
+    }
+
+    /// Blocks until either the x11 connection, one of the ipc sockets or the config watch has
+    /// something to read, or until the next ping times out or the config should be reloaded.
+    /// False when the x11 connection hung up or failed
+    fn wait_for_input(&self) -> bool {
+        let mut fds = vec![PollFd::new(self.x11.fd(), PollFlags::POLLIN)];
+        if let Some(w) = &self.config_watch {
+            fds.push(PollFd::new(w.fd(), PollFlags::POLLIN));
+        }
+        if let Some(ipc) = &self.ipc {
+            fds.extend(
+                ipc.fds()
+                    .into_iter()
+                    .map(|fd| PollFd::new(fd, PollFlags::POLLIN)),
+            );
+        }
+
+        // Rounded up so we don't wake up right before the deadline
+        let timeout = self
+            .pings
+            .values()
+            .chain(&self.reload_at)
+            .min()
+            .map(|d| d.saturating_duration_since(Instant::now()).as_millis() + 1)
+            .map_or(PollTimeout::NONE, |ms| {
+                PollTimeout::try_from(ms).unwrap_or(PollTimeout::MAX)
+            });
+
+        match poll(&mut fds, timeout) {
+            Ok(_) | Err(Errno::EINTR) => {}
+            Err(e) => warn!("Failed to wait for events: {e}"),
+        }
+        !fds[0]
+            .revents()
+            .is_some_and(|r| r.intersects(PollFlags::POLLHUP | PollFlags::POLLERR))
+    }
+
+    fn handle_ipc(&mut self) {
+        let requests = match &mut self.ipc {
+            Some(ipc) => ipc.read_requests(),
+            None => return,
+        };
+
+        for (client, request) in requests {
+            let reply = match request.and_then(|c| self.run_ipc_command(client, c)) {
+                Ok(serde_json::Value::Null) => serde_json::json!({ "ok": true }),
+                Ok(data) => serde_json::json!({ "ok": true, "data": data }),
+                Err(e) => serde_json::json!({ "ok": false, "error": e }),
+            };
+            if let Some(ipc) = &mut self.ipc {
+                ipc.reply(client, &reply);
+            }
+        }
+    }
+
+    /// Returns the data queries asked for, `Null` for everything else
+    fn run_ipc_command(
+        &mut self,
+        client: ipc::ClientId,
+        command: ipc::Command,
+    ) -> Result<serde_json::Value, String> {
+        match command {
+            ipc::Command::Subscribe(kinds) => {
+                if let Some(ipc) = &mut self.ipc {
+                    ipc.subscribe(client, kinds);
+                }
+            }
+            ipc::Command::GetWorkspaces => return Ok(self.workspaces_json()),
+            ipc::Command::GetTree => return Ok(self.tree_json()),
+            ipc::Command::Action(a) => action_to_fn(a)(self),
+            ipc::Command::SwitchWs(ws) => self.switch_ws(ws),
+            ipc::Command::MoveToWs(ws) => {
+                if self.focused().is_none() {
+                    return Err(String::from("there is no focused window"));
+                }
+                self.move_focused_to_ws(ws);
+            }
+        }
+        Ok(serde_json::Value::Null)
+    }
+
+    /// Notifies ipc subscribers, `data` is only built when someone is listening for `kind`
+    fn emit_event(&mut self, kind: ipc::EventKind, data: impl FnOnce(&Self) -> serde_json::Value) {
+        if !self.ipc.as_ref().is_some_and(|i| i.has_subscribers(kind)) {
+            return;
+        }
+        let data = data(self);
+        if let Some(ipc) = &mut self.ipc {
+            ipc.broadcast(kind, data);
+        }
+    }
+
+    fn window_json(&self, id: WindowId) -> serde_json::Value {
+        let (instance, class) = self.window_class(id).unzip();
+        serde_json::json!({
+            "id": id,
+            "class": class,
+            "instance": instance,
+            "title": self.window_title(id),
+            "floating": self.workspaces.iter().any(|ws| ws.floating.contains_key(&id)),
+            "fullscreen": self.fullscreen.contains(&id),
+            "workspace": self.workspace_of(id),
+        })
+    }
+
+    fn workspaces_json(&self) -> serde_json::Value {
+        self.workspaces
+            .iter()
+            .enumerate()
+            .map(|(i, ws)| {
+                serde_json::json!({
+                    "index": i,
+                    "name": self.settings.workspace_name(i),
+                    "current": i == self.curr_workspace,
+                    "layout": ws.layout.to_string(),
+                    "focused": ws.get_focused_id(),
+                    "tiled": ws.windows(),
+                    "floating": ws.floating.keys().collect::<Vec<_>>(),
+                })
+            })
+            .collect()
+    }
+
+    /// The whole state of the window manager, with titles and classes of every window resolved
+    fn tree_json(&self) -> serde_json::Value {
+        let workspaces = self
+            .workspaces
+            .iter()
+            .enumerate()
+            .map(|(i, ws)| {
+                let floating = ws
+                    .floating
+                    .iter()
+                    .map(|(id, g)| {
+                        let mut w = self.window_json(*id);
+                        w["geometry"] = serde_json::json!({ "x": g.x, "y": g.y, "w": g.w, "h": g.h });
+                        w
+                    })
+                    .collect::<Vec<_>>();
+                serde_json::json!({
+                    "index": i,
+                    "name": self.settings.workspace_name(i),
+                    "current": i == self.curr_workspace,
+                    "layout": ws.layout.to_string(),
+                    "focused": ws.get_focused_id(),
+                    "tiled": ws.windows().iter().map(|id| self.window_json(*id)).collect::<Vec<_>>(),
+                    "floating": floating,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let struts = self
+            .struts
+            .iter()
+            .map(|(id, s)| {
+                serde_json::json!({
+                    "window": id,
+                    "left": s.left,
+                    "right": s.right,
+                    "top": s.top,
+                    "bottom": s.bottom,
+                    "left_y": [s.left_start_y, s.left_end_y],
+                    "right_y": [s.right_start_y, s.right_end_y],
+                    "top_x": [s.top_start_x, s.top_end_x],
+                    "bottom_x": [s.bottom_start_x, s.bottom_end_x],
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let s = &self.settings;
+        let workspace_layouts = s
+            .workspace_layouts
+            .iter()
+            .map(|l| l.as_ref().map(|l| l.to_string()))
+            .collect::<Vec<_>>();
+        let rules = s
+            .rules
+            .iter()
+            .map(|r| {
+                serde_json::json!({
+                    "class": r.class,
+                    "instance": r.instance,
+                    "title": r.title,
+                    "type": r.window_type,
+                    "workspace": r.workspace,
+                    "floating": r.floating,
+                    "geometry": r.geometry,
+                    "border": r.border_width,
+                    "focus": r.focus,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let (width, height) = self.x11.screen_size();
+        serde_json::json!({
+            "screen": { "width": width, "height": height },
+            "current_workspace": self.curr_workspace,
+            "focused": self.focused(),
+            "workspaces": workspaces,
+            "struts": struts,
+            "settings": {
+                "master_key": format!("{:?}", s.master_key),
+                "gap": s.gap,
+                "terminal": s.terminal,
+                "launcher": s.launcher,
+                "border_width": s.border_width,
+                "border_active_color": format!("#{:06x}", s.border_active_color & 0xffffff),
+                "border_inactive_color": format!("#{:06x}", s.border_inactive_color & 0xffffff),
+                "border_unresponsive_color": format!("#{:06x}", s.border_unresponsive_color & 0xffffff),
+                "master_ratio": s.master_ratio,
+                "auto_reload": s.auto_reload,
+                "reload_notice": s.reload_notice,
+                "master_count": s.master_count,
+                "layout": s.layout.to_string(),
+                "workspace_layouts": workspace_layouts,
+                "workspace_names": s.workspace_names,
+                "custom_layouts": s.custom_layouts.iter().map(|l| &l.name).collect::<Vec<_>>(),
+                "rules": rules,
+            },
+        })
+    }
+
+    fn handle_event(&mut self, event: Event) {
+        match event {
+            Event::MapRequest(e) => self.add_window(e),
+            Event::UnmapNotify(e) => self.remove_window(e),
+            Event::KeyPress(e) => {
+                for b in &self.binds.clone() {
+                    b.try_do(self, e);
+                }
+            }
+            Event::MotionNotify(_) => {
+                // Every tiled window in monocle shares the same rect, so the pointer can't pick.
+                // A fullscreen window covers every other one, the pointer can't leave it
+                if self.suppress_cursor_focus
+                    || self.curr_ws().layout == Layout::Monocle
+                    || self.fullscreen_on_curr_ws()
+                {
+                    return;
+                }
+                let (x, y) = self.x11.mouse_pos();
+                if self.last_x != x || self.last_y != y {
+                    let rects = self.laid_out_rects();
+                    for (i, r) in rects.iter() {
+                        if x > r.x && x < r.x + r.w && y > r.y && y < r.y + r.h {
+                            self.curr_ws_mut().set_focused_id(*i);
+                            self.set_focus(*i);
+                        }
+                    }
+                    self.last_x = x;
+                    self.last_y = y;
+                }
+            }
+            Event::EnterNotify(e) => {
+                self.curr_ws_mut().set_focused_id(e.event);
+                self.set_focus(e.event);
+            }
+            Event::KeyRelease(_) => {}
+            Event::MappingNotify(_) => {}
+            Event::ConfigureRequest(_) => self.layout(),
+            Event::PropertyNotify(e) => {
+                let title_atoms = [Some(AtomEnum::WM_NAME.into()), self.net_wm_name_atom];
+                if title_atoms.contains(&Some(e.atom)) && self.workspace_of(e.window).is_some() {
+                    self.emit_event(
+                        ipc::EventKind::Title,
+                        |nwm| serde_json::json!({ "window": nwm.window_json(e.window) }),
+                    );
+                    return;
+                }
+                if self.strut_partial_atom.is_none() {
+                    return;
+                }
+                let spa = self.strut_partial_atom.unwrap();
+                if e.atom == spa
+                    && let Some(strut) = self.get_strut_partial(e.window, spa)
+                {
+                    self.struts.insert(e.window, Strut::from(strut));
+                    self.layout();
+                }
+            }
+            Event::SelectionClear(e) if e.selection == self.x11.wm_selection() => {
+                info!("Another window manager is replacing nwm");
+                self.show_all_windows();
+                self.running = false;
+            }
+            Event::ClientMessage(e) => {
+                let data = e.data.as_data32();
+                if Some(e.type_) == self.wm_protocols_atom && Some(data[0]) == self.net_wm_ping_atom
+                {
+                    self.handle_pong(data[2]);
+                } else {
+                    self.handle_ewmh_message(e);
+                }
+            }
+            Event::DestroyNotify(e) => {
+                self.forget_window(e.window);
+                self.struts.remove(&e.window);
+                self.layout();
+            }
+
+            Event::CreateNotify(_) | Event::MapNotify(_) | Event::ConfigureNotify(_) => {}
+            _ => {
+                warn!("Skipping event: {:#?}", event);
+            }
+        }
+    }
+
+    fn focus_on_pointer(&mut self) {
+        if self.fullscreen_on_curr_ws() {
+            return;
+        }
+        let rects = self.floating_window_rects();
+
+        for (id, r) in rects.iter() {
+            if self.last_x > r.x
+                && self.last_x < r.x + r.w
+                && self.last_y > r.y
+                && self.last_y < r.y + r.h
+            {
+                self.set_window_border_pixel(*id, self.settings.border_inactive_color);
+                self.curr_ws_mut().set_focused_id(*id);
+                self.set_focus(*id);
+                return;
+            }
+        }
+
+        if self.curr_ws().layout == Layout::Monocle {
+            if let Some(id) = self.curr_ws().get_focused_id() {
+                self.set_focus(id);
+            }
+            return;
+        }
+
+        let rects = self.laid_out_rects();
+        for (i, r) in rects.iter() {
+            if self.last_x > r.x
+                && self.last_x < r.x + r.w
+                && self.last_y > r.y
+                && self.last_y < r.y + r.h
+            {
+                self.set_window_border_pixel(*i, self.settings.border_inactive_color);
+                self.curr_ws_mut().set_focused_id(*i);
+                self.set_focus(*i);
+                return;
+            }
+        }
+    }
+
+    fn set_window_border_pixel(&mut self, w: WindowId, color: u32) {
+        // Hung windows keep their color until they answer a ping or get killed
+        let color = if self.unresponsive.contains(&w) {
+            self.settings.border_unresponsive_color
+        } else {
+            color
+        };
+        _ = self
+            .x11
+            .conn
+            .change_window_attributes(w, &ChangeWindowAttributesAux::new().border_pixel(color));
+    }
+
+    fn set_window_border_width(&mut self, w: WindowId, width: u8) {
+        self.x11
+            .conn
+            .configure_window(w, &ConfigureWindowAux::new().border_width(width as u32))
+            .unwrap();
+    }
+
+    fn switch_ws(&mut self, new_ws: usize) {
+        if new_ws >= self.workspaces.len() || new_ws == self.curr_workspace {
+            return;
+        }
+
+        let old_ws = self.curr_workspace;
+
+        for &w in self.workspaces[old_ws].windows() {
+            self.x11.unmap_window(w).unwrap();
+        }
+
+        for w in self.workspaces[old_ws].floating.keys() {
+            self.x11.unmap_window(*w).unwrap();
+        }
+
+        self.curr_workspace = new_ws;
+
+        for &w in self.workspaces[new_ws].windows() {
+            self.x11.map_window(w).unwrap();
+        }
+
+        for w in self.workspaces[new_ws].floating.keys() {
+            self.x11.map_window(*w).unwrap();
         }
 
-        rs
+        self.update_current_desktop();
+
+        self.layout();
+        self.focus_on_pointer();
+        self.sync_active_window();
+
+        self.fire_hook(lua_cfg::Hook::Workspace, |_, lua| {
+            let t = lua.create_table()?;
+            t.set("workspace", new_ws)?;
+            t.set("previous", old_ws)?;
+            Ok(t)
+        });
+        self.emit_event(
+            ipc::EventKind::Workspace,
+            |_| serde_json::json!({ "workspace": new_ws, "previous": old_ws }),
+        );
+    }
+
+    /// The rects `layout` last put the tiled windows in, minus windows that left the workspace
+    /// since then. Used for the pointer, so a custom layout doesn't run on every motion event
+    fn laid_out_rects(&self) -> Vec<(WindowId, Rect)> {
+        self.tiled_rects
+            .iter()
+            .filter(|(w, _)| self.curr_ws().windows().contains(w))
+            .copied()
+            .collect()
+    }
+
+    fn tiled_window_rects(&self) -> Vec<(WindowId, Rect)> {
+        if self.curr_ws().empty() {
+            return vec![];
+        }
+
+        let (sw, sh) = self.x11.screen_size();
+        let reserved = self.get_reserved_space();
+
+        let area = Rect {
+            x: reserved.x0 as i16,
+            y: reserved.y0 as i16,
+            w: sw as i16 - (reserved.x0 + reserved.x1) as i16,
+            h: sh as i16 - (reserved.y0 + reserved.y1) as i16,
+        };
+
+        let params = LayoutParams {
+            gap: self.settings.gap as i16,
+            master_ratio: self.settings.master_ratio,
+            master_count: self.settings.master_count,
+        };
+
+        let ws = self.curr_ws();
+        ws.layout
+            .arrange(
+                ws.window_count(),
+                area,
+                &params,
+                &self.settings.custom_layouts,
+            )
+            .into_iter()
+            .enumerate()
+            .filter(|(_, r)| r.w > 0 && r.h > 0)
+            .map(|(i, r)| (*ws.get_tiled_window_id(i).unwrap(), r))
+            .collect()
     }
 
     fn floating_window_rects(&self) -> Vec<(WindowId, Rect)> {
@@ -876,81 +2049,409 @@ impl Nwm {
     }
 
     fn add_window(&mut self, event: MapRequestEvent) {
-        self.x11.map_window(event.window).unwrap();
+        self.manage_window(event.window, None);
+    }
+
+    /// Puts a window on `workspace` (or where the rules say, or the current one) and lays it out.
+    /// Shared by newly mapped windows and the ones adopted on startup
+    fn manage_window(&mut self, window: WindowId, workspace: Option<usize>) {
         if let Some(spa) = self.strut_partial_atom
-            && let Some(strut) = self.get_strut_partial(event.window, spa)
+            && let Some(strut) = self.get_strut_partial(window, spa)
         {
-            self.struts.insert(event.window, Strut::from(strut));
+            self.struts.insert(window, Strut::from(strut));
             self.layout();
         }
-        if self.window_is_dock(event.window) {
+        if self.window_is_dock(window) {
+            self.x11.map_window(window).unwrap();
             return;
         }
-        if self.window_is_normal(event.window) {
-            self.x11
-                .conn
-                .change_window_attributes(
-                    event.window,
-                    &ChangeWindowAttributesAux::new().event_mask(EventMask::ENTER_WINDOW),
-                )
-                .unwrap();
-            self.set_window_border_width(event.window, self.border_width);
-            self.set_window_border_pixel(event.window, self.inactive_border_color);
-            self.curr_ws_mut().push_window(event.window);
-            self.curr_ws_mut().set_focused_to_newest_tiled_window();
+
+        let rule = self.rule_for(window);
+        let ws = workspace.or(rule.workspace).unwrap_or(self.curr_workspace);
+        let visible = ws == self.curr_workspace;
+        let focus = rule.focus.unwrap_or(true);
+        let border_width = rule.border_width.unwrap_or(self.settings.border_width) as u8;
+
+        // Windows going to another workspace get mapped once that one is switched to, adopted
+        // ones may already be mapped so they have to be hidden
+        if visible {
+            self.x11.map_window(window).unwrap();
+        } else {
+            self.x11.unmap_window(window);
+        }
+        self.select_client_events(window);
+
+        if !rule.floating.unwrap_or(!self.window_is_normal(window)) {
+            self.set_window_border_width(window, border_width);
+            self.set_window_border_pixel(window, self.settings.border_inactive_color);
+            self.workspaces[ws].push_window(window);
+            if focus {
+                self.workspaces[ws].set_focused_to_newest_tiled_window();
+            }
             self.layout();
-            self.x11.focus_window(event.window);
+            if visible && focus {
+                self.x11.focus_window(window);
+            }
         } else {
-            self.x11
+            let (x, y, w, h) = rule
+                .geometry
+                .unwrap_or_else(|| self.centered_geometry(window));
+            self.workspaces[ws].push_float_window(window, Geometry { x, y, w, h });
+            if visible && focus {
+                self.set_focus(window);
+            }
+            self.set_window_border_width(window, border_width);
+            self.set_window_border_pixel(window, self.settings.border_inactive_color);
+
+            self.x11.resize_window(window, w as u32, h as u32);
+            self.x11.move_window(window, x, y);
+            if visible {
+                self.raise(window);
+            }
+            if visible && focus {
+                self.x11.focus_window(window);
+            }
+        }
+
+        if !self.clients.contains(&window) {
+            self.clients.push(window);
+        }
+        self.set_window_desktop(window, ws);
+        self.apply_requested_fullscreen(window);
+        self.update_client_list();
+
+        self.fire_hook(lua_cfg::Hook::WindowOpen, |nwm, lua| {
+            nwm.window_lua_table(lua, window)
+        });
+        self.emit_event(
+            ipc::EventKind::WindowOpen,
+            |nwm| serde_json::json!({ "window": nwm.window_json(window) }),
+        );
+    }
+
+    /// Maps the windows of every workspace, so a window manager replacing nwm can adopt them
+    fn show_all_windows(&mut self) {
+        let windows = self
+            .workspaces
+            .iter()
+            .flat_map(|ws| ws.windows().iter().chain(ws.floating.keys()))
+            .copied()
+            .collect::<Vec<_>>();
+        for w in windows {
+            self.x11.map_window(w);
+        }
+    }
+
+    fn select_client_events(&mut self, window: WindowId) {
+        self.x11
+            .conn
+            .change_window_attributes(
+                window,
+                &ChangeWindowAttributesAux::new()
+                    .event_mask(EventMask::ENTER_WINDOW | EventMask::PROPERTY_CHANGE),
+            )
+            .unwrap();
+    }
+
+    fn session(&self) -> session::Session {
+        session::Session {
+            current_workspace: self.curr_workspace,
+            workspaces: self
+                .workspaces
+                .iter()
+                .map(|ws| session::SavedWorkspace {
+                    tiled: ws.windows().to_vec(),
+                    focused: ws.get_focused_id(),
+                    floating: ws
+                        .floating
+                        .iter()
+                        .map(|(id, g)| (*id, (g.x, g.y, g.w, g.h)))
+                        .collect(),
+                    layout: ws.layout.to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Saves the session and re-executes nwm (picking up a rebuilt binary), the new process
+    /// restores the session in `create`
+    fn restart(&mut self) {
+        let path = session::state_path(&self.display_name);
+        if let Err(e) = self.session().save(&path) {
+            warn!(
+                "Failed to save the session to {}: {e}, not restarting",
+                path.display()
+            );
+            return;
+        }
+
+        let mut args = std::env::args_os();
+        let program = args
+            .next()
+            .map(PathBuf::from)
+            .or_else(|| std::env::current_exe().ok())
+            .unwrap_or_else(|| PathBuf::from("nwm"));
+        info!("Restarting {}", program.display());
+
+        // exec doesn't run destructors, the socket has to be gone for the new process to bind it.
+        // The x11 connection only gets closed by exec, which the server might notice after the
+        // new process checks for a running window manager
+        self.ipc = None;
+        self.x11.release_wm();
+        let e = Command::new(&program).args(args).exec();
+
+        warn!("Failed to restart {}: {e}", program.display());
+        _ = std::fs::remove_file(&path);
+        if self.x11.reacquire_wm().is_none() {
+            self.running = false;
+            return;
+        }
+        self.publish_ewmh_support();
+        self.ipc = ipc::IpcServer::bind(ipc::socket_path(&self.display_name));
+    }
+
+    /// Puts the windows back where they were before `restart`, windows closed in the meantime are
+    /// skipped
+    fn restore_session(&mut self, session: session::Session) {
+        info!("Restoring the session from before the restart");
+        self.curr_workspace = session.current_workspace.min(self.workspaces.len() - 1);
+
+        for (i, saved) in session
+            .workspaces
+            .into_iter()
+            .enumerate()
+            .take(self.workspaces.len())
+        {
+            if let Some(layout) = Layout::from_name(&saved.layout, &self.settings.custom_layouts) {
+                self.workspaces[i].layout = layout;
+            }
+            for w in saved.tiled {
+                if self.restore_window(w, i) {
+                    self.workspaces[i].push_window(w);
+                }
+            }
+            for (w, (x, y, width, height)) in saved.floating {
+                if self.restore_window(w, i) {
+                    self.workspaces[i].push_float_window(
+                        w,
+                        Geometry {
+                            x,
+                            y,
+                            w: width,
+                            h: height,
+                        },
+                    );
+                    self.x11.resize_window(w, width as u32, height as u32);
+                    self.x11.move_window(w, x, y);
+                }
+            }
+            let ws = &mut self.workspaces[i];
+            ws.focused = saved
+                .focused
+                .filter(|f| ws.windows.contains(f) || ws.floating.contains_key(f));
+        }
+        for w in self.clients.clone() {
+            self.apply_requested_fullscreen(w);
+        }
+
+        self.layout();
+        if let Some(f) = self.focused() {
+            self.set_focus(f);
+        }
+    }
+
+    fn restore_window(&mut self, window: WindowId, ws: usize) -> bool {
+        let exists = self
+            .x11
+            .conn
+            .get_window_attributes(window)
+            .ok()
+            .and_then(|c| c.reply().ok())
+            .is_some_and(|a| !a.override_redirect);
+        if !exists {
+            return false;
+        }
+
+        let border_width = self
+            .rule_for(window)
+            .border_width
+            .unwrap_or(self.settings.border_width);
+        self.select_client_events(window);
+        self.set_window_border_width(window, border_width as u8);
+        self.set_window_border_pixel(window, self.settings.border_inactive_color);
+        if ws == self.curr_workspace {
+            self.x11.map_window(window);
+        } else {
+            self.x11.unmap_window(window);
+        }
+        self.clients.push(window);
+        self.set_window_desktop(window, ws);
+        true
+    }
+
+    /// Manages the windows that were already mapped before nwm started (e.g. after a crash), or hidden
+    /// on another workspace, placing them on the workspace in their `_NET_WM_DESKTOP`
+    fn adopt_windows(&mut self) {
+        let Some(tree) = self
+            .x11
+            .conn
+            .query_tree(self.x11.root_window())
+            .ok()
+            .and_then(|c| c.reply().ok())
+        else {
+            warn!("Failed to query the existing windows, they won't be managed");
+            return;
+        };
+
+        for window in tree.children {
+            let Some(attrs) = self
+                .x11
                 .conn
-                .change_window_attributes(
-                    event.window,
-                    &ChangeWindowAttributesAux::new().event_mask(EventMask::ENTER_WINDOW),
-                )
-                .unwrap();
-            let (w, h) =
-                x11rb::properties::WmSizeHints::get_normal_hints(&self.x11.conn, event.window)
+                .get_window_attributes(window)
+                .ok()
+                .and_then(|c| c.reply().ok())
+            else {
+                continue;
+            };
+            if attrs.override_redirect || self.workspace_of(window).is_some() {
+                continue;
+            }
+            let desktop = self.window_desktop(window);
+            // Windows nwm (or another window manager) hid on other workspaces are unmapped, but
+            // still carry their desktop or a normal/iconic WM_STATE, withdrawn ones carry neither
+            let hidden = attrs.map_state == MapState::UNMAPPED
+                && (desktop.is_some()
+                    || matches!(
+                        self.window_wm_state(window),
+                        Some(WM_STATE_NORMAL | WM_STATE_ICONIC)
+                    ));
+            if attrs.map_state != MapState::VIEWABLE && !hidden {
+                continue;
+            }
+
+            let desktop = desktop.filter(|d| *d < 10);
+            info!("Adopting window {window} (workspace {desktop:?})");
+            self.manage_window(window, desktop);
+        }
+    }
+
+    /// The state in the ICCCM `WM_STATE` of a window
+    fn window_wm_state(&self, w: WindowId) -> Option<u32> {
+        let wm_state = self.wm_state_atom?;
+        self.x11
+            .conn
+            .get_property(false, w, wm_state, wm_state, 0, 1)
+            .ok()?
+            .reply()
+            .ok()?
+            .value32()?
+            .next()
+    }
+
+    /// `_NET_WM_DESKTOP` of a window
+    fn window_desktop(&self, w: WindowId) -> Option<usize> {
+        self.x11
+            .conn
+            .get_property(
+                false,
+                w,
+                self.net_wm_desktop_atom?,
+                AtomEnum::CARDINAL,
+                0,
+                1,
+            )
+            .ok()?
+            .reply()
+            .ok()?
+            .value32()?
+            .next()
+            .map(|d| d as usize)
+    }
+
+    /// Size hints (or the current size) of a floating window, centered on the screen
+    fn centered_geometry(&self, window: WindowId) -> (i16, i16, i16, i16) {
+        let (w, h) = x11rb::properties::WmSizeHints::get_normal_hints(&self.x11.conn, window)
+            .ok()
+            .and_then(|c| c.reply().ok())
+            .and_then(|h| h)
+            .and_then(|h| h.min_size)
+            .or_else(|| {
+                self.x11
+                    .conn
+                    .get_geometry(window)
                     .ok()
                     .and_then(|c| c.reply().ok())
-                    .and_then(|h| h)
-                    .and_then(|h| h.min_size)
-                    .or_else(|| {
-                        self.x11
-                            .conn
-                            .get_geometry(event.window)
-                            .ok()
-                            .and_then(|c| c.reply().ok())
-                            .map(|g| (g.width as i32, g.height as i32))
-                    })
-                    .unwrap_or((200, 150));
-            let (sw, sh) = self.x11.screen_size();
-            let (x, y) = (
-                (sw / 2) as i16 - (w / 2) as i16,
-                (sh / 2) as i16 - (h / 2) as i16,
-            );
-            self.curr_ws_mut().push_float_window(
-                event.window,
-                Geometry {
-                    x,
-                    y,
-                    w: w as i16,
-                    h: h as i16,
-                },
-            );
-            self.set_focus(event.window);
-            self.set_window_border_width(event.window, self.border_width);
-            self.set_window_border_pixel(event.window, self.inactive_border_color);
+                    .map(|g| (g.width as i32, g.height as i32))
+            })
+            .unwrap_or((200, 150));
+        let (sw, sh) = self.x11.screen_size();
+        (
+            (sw / 2) as i16 - (w / 2) as i16,
+            (sh / 2) as i16 - (h / 2) as i16,
+            w as i16,
+            h as i16,
+        )
+    }
+
+    /// Every rule matching `window` merged together, later rules win
+    fn rule_for(&self, window: WindowId) -> lua_cfg::Rule {
+        let mut rule = lua_cfg::Rule::default();
+        let Some(lua) = &self.lua.lua else {
+            return rule;
+        };
+        if self.settings.rules.is_empty() {
+            return rule;
+        }
 
-            self.x11.resize_window(event.window, w as u32, h as u32);
-            self.x11.move_window(event.window, x, y);
-            self.x11.raise_window(event.window);
-            self.x11.focus_window(event.window);
+        let (instance, class) = self.window_class(window).unzip();
+        let props = lua_cfg::WindowProps {
+            class,
+            instance,
+            title: self.window_title(window),
+            types: self.window_type_names(window),
+        };
+
+        for r in &self.settings.rules {
+            match r.matches(lua, &props) {
+                Ok(true) => rule.merge(r),
+                Ok(false) => {}
+                Err(e) => warn!("Failed to match rule {r:?} against window {window}: {e}"),
+            }
         }
+        rule
+    }
+
+    /// _NET_WM_WINDOW_TYPE of a window as `normal`, `dialog`, ...
+    fn window_type_names(&self, w: WindowId) -> Vec<String> {
+        let Some(wta) = self.window_type_atom else {
+            return vec![];
+        };
+        self.get_window_type(w, wta)
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|a| self.x11.conn.get_atom_name(a).ok()?.reply().ok())
+            .map(|n| {
+                let name = String::from_utf8_lossy(&n.name);
+                name.strip_prefix("_NET_WM_WINDOW_TYPE_")
+                    .unwrap_or(&name)
+                    .to_lowercase()
+            })
+            .collect()
     }
 
     fn remove_window(&mut self, event: UnmapNotifyEvent) {
         self.struts.remove(&event.window);
-        self.curr_ws_mut().remove_window(event.window);
+        // Windows we unmap ourselves (switching workspaces, ...) aren't part of the current one
+        // anymore, so only those closing on their own count
+        let managed = self.curr_ws().windows().contains(&event.window)
+            || self.curr_ws().floating.contains_key(&event.window);
+        if managed {
+            // A withdrawn window doesn't have a desktop, it must not be adopted after a restart
+            if let Some(atom) = self.net_wm_desktop_atom {
+                _ = self.x11.conn.delete_property(event.window, atom);
+            }
+            self.unmanage(event.window);
+        }
         self.layout();
     }
 
@@ -977,20 +2478,20 @@ impl Nwm {
     fn launcher(&mut self) {
         let _ = Command::new("sh")
             .arg("-c")
-            .arg(&self.launcher)
+            .arg(&self.settings.launcher)
             .spawn()
             .map_err(|e| {
-                warn!("Failed to launch launcher {}: {e}", &self.launcher);
+                warn!("Failed to launch launcher {}: {e}", &self.settings.launcher);
             });
     }
 
     fn terminal(&mut self) {
         let _ = Command::new("sh")
             .arg("-c")
-            .arg(&self.terminal)
+            .arg(&self.settings.terminal)
             .spawn()
             .map_err(|e| {
-                warn!("Failed to launch terminal {}: {e}", &self.terminal);
+                warn!("Failed to launch terminal {}: {e}", &self.settings.terminal);
             });
     }
 
@@ -1010,10 +2511,12 @@ impl Nwm {
 
     fn layout(&mut self) {
         if self.curr_ws().empty() {
+            self.tiled_rects.clear();
             return;
         }
 
         let rects = self.tiled_window_rects();
+        self.tiled_rects = rects.clone();
 
         for (w, r) in rects.iter() {
             if self.window_is_dock(*w)
@@ -1039,25 +2542,223 @@ impl Nwm {
                 );
                 continue;
             }
+            if self.fullscreen.contains(w) {
+                continue;
+            }
             self.x11.move_window(*w, r.x, r.y).unwrap();
             self.x11.resize_window(*w, r.w as u32, r.h as u32).unwrap();
         }
+
+        self.raise_monocle_focused();
+        self.raise_fullscreen();
+    }
+
+    fn toggle_fullscreen(&mut self) {
+        if let Some(w) = self.focused() {
+            let on = !self.fullscreen.contains(&w);
+            self.set_fullscreen(w, on);
+        }
+    }
+
+    /// Stretches a window over the whole screen (ignoring gaps, borders and struts) above
+    /// everything else, or puts it back into its tiled slot or floating geometry
+    fn set_fullscreen(&mut self, w: WindowId, on: bool) {
+        if let Some(fs) = self.net_wm_state_fullscreen_atom {
+            self.set_window_state(w, fs, on);
+        }
+        if self.fullscreen.contains(&w) == on {
+            return;
+        }
+
+        if on {
+            self.fullscreen.insert(w);
+            self.set_window_border_width(w, 0);
+        } else {
+            self.fullscreen.remove(&w);
+            let border_width = self
+                .rule_for(w)
+                .border_width
+                .unwrap_or(self.settings.border_width);
+            self.set_window_border_width(w, border_width as u8);
+            if let Some(g) = self
+                .workspace_of(w)
+                .and_then(|ws| self.workspaces[ws].floating.get(&w).copied())
+            {
+                self.x11.resize_window(w, g.w as u32, g.h as u32);
+                self.x11.move_window(w, g.x, g.y);
+            }
+        }
+        self.update_client_list();
+        // Lays the tiled slot out again and raises the window when it's fullscreen
+        self.layout();
+    }
+
+    /// Windows can ask for fullscreen before they get mapped by putting it in their `_NET_WM_STATE`,
+    /// which also keeps it across a restart
+    fn apply_requested_fullscreen(&mut self, w: WindowId) {
+        if let Some(fs) = self.net_wm_state_fullscreen_atom
+            && self.window_state(w).contains(&fs)
+        {
+            self.set_fullscreen(w, true);
+        }
+    }
+
+    fn fullscreen_on_curr_ws(&self) -> bool {
+        self.fullscreen
+            .iter()
+            .any(|w| self.workspace_of(*w) == Some(self.curr_workspace))
+    }
+
+    /// Gives the fullscreen windows of the current workspace the whole screen, on top of docks
+    /// and floating windows
+    fn raise_fullscreen(&mut self) {
+        let (sw, sh) = self.x11.screen_size();
+        let windows = self
+            .fullscreen
+            .iter()
+            .copied()
+            .filter(|w| self.workspace_of(*w) == Some(self.curr_workspace))
+            .collect::<Vec<_>>();
+        for w in windows {
+            self.x11.move_window(w, 0, 0);
+            self.x11.resize_window(w, sw as u32, sh as u32);
+            self.raise(w);
+        }
+    }
+
+    /// In monocle every tiled window is stacked on top of each other, so the focused one has to be
+    /// raised to be visible. Floating windows are raised after it to stay on top
+    fn raise_monocle_focused(&mut self) {
+        if self.curr_ws().layout != Layout::Monocle {
+            return;
+        }
+        let Some(id) = self.curr_ws().get_focused_id() else {
+            return;
+        };
+        let Some(p) = self.curr_ws().windows().iter().position(|w| *w == id) else {
+            return;
+        };
+
+        self.raise(id);
+        for w in self.curr_ws().floating.keys().copied().collect::<Vec<_>>() {
+            self.raise(w);
+        }
+        self.raise_fullscreen();
+
+        info!(
+            "Monocle on workspace {}: showing window {}/{}",
+            self.curr_workspace,
+            p + 1,
+            self.curr_ws().window_count()
+        );
     }
 
     fn set_focus(&mut self, id: WindowId) {
+        let changed = self.last_focused != Some(id);
         if let Some(prev) = self.last_focused {
-            self.set_window_border_pixel(prev, self.inactive_border_color);
+            self.set_window_border_pixel(prev, self.settings.border_inactive_color);
         }
 
-        self.set_window_border_pixel(id, self.active_border_color);
+        self.set_window_border_pixel(id, self.settings.border_active_color);
         let _ = self.x11.focus_window(id);
 
         self.curr_ws_mut().focused = Some(id);
         self.last_focused = Some(id);
+        self.sync_active_window();
+
+        self.raise_monocle_focused();
+
+        if changed {
+            self.fire_hook(lua_cfg::Hook::Focus, |nwm, lua| {
+                nwm.window_lua_table(lua, id)
+            });
+            self.emit_event(
+                ipc::EventKind::Focus,
+                |nwm| serde_json::json!({ "window": nwm.window_json(id) }),
+            );
+        }
+    }
+}
+
+/// `~/.config/nwm/config.lua`, creating the directory if it doesn't exist
+fn default_config_path() -> PathBuf {
+    let dirs = platform_dirs::AppDirs::new(Some("nwm"), false).unwrap();
+    _ = std::fs::create_dir(&dirs.config_dir);
+    dirs.config_dir.join("config.lua")
+}
+
+fn init_logging(args: &cli::Args) -> Result<(), String> {
+    let file = std::fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(&args.log_file)
+        .map_err(|e| format!("can't open the log file {}: {e}", args.log_file.display()))?;
+
+    multi_log::MultiLog::init(
+        vec![
+            Box::new(env_logger::Logger::from_default_env()),
+            Box::new(nw_log_connection::NwLogLog::init(file, args.log_level)),
+        ],
+        log::Level::Trace,
+    );
+    Ok(())
+}
+
+/// `--check-config`: prints every problem in the config without touching the display
+fn check_config(args: &cli::Args) -> ExitCode {
+    env_logger::Builder::new()
+        .filter_level(log::LevelFilter::Warn)
+        .init();
+
+    let path = args.config.clone().unwrap_or_else(default_config_path);
+    let problems = lua_cfg::check_config(&path);
+    if problems.is_empty() {
+        println!("{} is valid", path.display());
+        return ExitCode::SUCCESS;
+    }
+    for p in &problems {
+        eprintln!("{p}");
     }
+    eprintln!("{} problem(s) found", problems.len());
+    ExitCode::FAILURE
 }
 
-fn main() {
-    let display_name = std::env::var("DISPLAY").unwrap();
-    Nwm::create(&display_name).unwrap().run();
+fn main() -> ExitCode {
+    let args = match cli::parse(std::env::args().skip(1)) {
+        Ok(cli::Cli::Run(args)) => args,
+        Ok(cli::Cli::CheckConfig(args)) => return check_config(&args),
+        Ok(cli::Cli::Help) => {
+            println!("{}", cli::USAGE);
+            return ExitCode::SUCCESS;
+        }
+        Ok(cli::Cli::Version) => {
+            println!("nwm {}", env!("CARGO_PKG_VERSION"));
+            return ExitCode::SUCCESS;
+        }
+        Err(e) => {
+            eprintln!("nwm: {e}, see nwm --help");
+            return ExitCode::from(2);
+        }
+    };
+
+    let display_name = match args.display_name() {
+        Ok(d) => d,
+        Err(e) => {
+            eprintln!("nwm: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    // The terminal, launcher and everything the config runs have to end up on the display nwm
+    // manages, even when it was picked with --display.
+    // SAFETY: nothing else runs yet, so no other thread can be reading the environment
+    unsafe { std::env::set_var("DISPLAY", &display_name) };
+    if let Err(e) = init_logging(&args) {
+        eprintln!("nwm: {e}");
+        return ExitCode::FAILURE;
+    }
+
+    let Some(nwm) = Nwm::create(&args, &display_name) else {
+        return ExitCode::FAILURE;
+    };
+    nwm.run()
 }
@@ -1,16 +1,18 @@
 use colored::Colorize;
-use log::Level;
+use log::LevelFilter;
 use std::io::Write;
 use std::sync::Mutex;
 
 pub struct NwLogLog {
     out: Mutex<std::fs::File>,
+    level: LevelFilter,
 }
 
 impl NwLogLog {
-    pub fn init(stdin: std::fs::File) -> Self {
+    pub fn init(stdin: std::fs::File, level: LevelFilter) -> Self {
         Self {
             out: Mutex::new(stdin),
+            level,
         }
     }
 }
@@ -37,6 +39,6 @@ impl log::Log for NwLogLog {
     }
 
     fn enabled(&self, metadata: &log::Metadata) -> bool {
-        metadata.level() <= Level::Info
+        metadata.level() <= self.level
     }
 }
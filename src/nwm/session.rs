@@ -0,0 +1,177 @@
+use std::path::{Path, PathBuf};
+
+use log::warn;
+use serde_json::{Value, json};
+
+use crate::better_x11rb::WindowId;
+
+/// `$XDG_RUNTIME_DIR/nwm-$DISPLAY.state` (`/tmp` when there is no runtime dir)
+pub fn state_path(display: &str) -> PathBuf {
+    let dir = std::env::var("XDG_RUNTIME_DIR").unwrap_or_else(|_| String::from("/tmp"));
+    PathBuf::from(dir).join(format!("nwm-{display}.state"))
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SavedWorkspace {
+    pub tiled: Vec<WindowId>,
+    pub focused: Option<WindowId>,
+    /// Window and its (x, y, w, h)
+    pub floating: Vec<(WindowId, (i16, i16, i16, i16))>,
+    /// Name of the layout, as displayed
+    pub layout: String,
+}
+
+/// Everything `Action::Restart` carries over to the new nwm process
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Session {
+    pub current_workspace: usize,
+    pub workspaces: Vec<SavedWorkspace>,
+}
+
+impl Session {
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let workspaces = self
+            .workspaces
+            .iter()
+            .map(|ws| {
+                let floating = ws
+                    .floating
+                    .iter()
+                    .map(|(id, (x, y, w, h))| json!({ "id": id, "x": x, "y": y, "w": w, "h": h }))
+                    .collect::<Vec<_>>();
+                json!({
+                    "tiled": ws.tiled,
+                    "focused": ws.focused,
+                    "floating": floating,
+                    "layout": ws.layout,
+                })
+            })
+            .collect::<Vec<_>>();
+        let state = json!({
+            "current_workspace": self.current_workspace,
+            "workspaces": workspaces,
+        });
+        std::fs::write(path, state.to_string()).map_err(|e| e.to_string())
+    }
+
+    /// Reads and removes the state left behind by a restart, so it's only ever restored once
+    pub fn take(path: &Path) -> Option<Self> {
+        let state = std::fs::read_to_string(path).ok()?;
+        _ = std::fs::remove_file(path);
+
+        let session = serde_json::from_str::<Value>(&state)
+            .ok()
+            .and_then(|v| Self::from_json(&v));
+        if session.is_none() {
+            warn!("Ignoring invalid restart state in {}", path.display());
+        }
+        session
+    }
+
+    fn from_json(v: &Value) -> Option<Self> {
+        let id = |v: &Value| v.as_u64().map(|id| id as WindowId);
+        let coord = |v: &Value| v.as_i64().map(|c| c as i16);
+
+        let workspaces = v["workspaces"]
+            .as_array()?
+            .iter()
+            .map(|ws| {
+                let floating = ws["floating"]
+                    .as_array()?
+                    .iter()
+                    .map(|f| {
+                        Some((
+                            id(&f["id"])?,
+                            (
+                                coord(&f["x"])?,
+                                coord(&f["y"])?,
+                                coord(&f["w"])?,
+                                coord(&f["h"])?,
+                            ),
+                        ))
+                    })
+                    .collect::<Option<Vec<_>>>()?;
+                Some(SavedWorkspace {
+                    tiled: ws["tiled"]
+                        .as_array()?
+                        .iter()
+                        .map(id)
+                        .collect::<Option<_>>()?,
+                    focused: id(&ws["focused"]),
+                    floating,
+                    layout: ws["layout"].as_str()?.to_string(),
+                })
+            })
+            .collect::<Option<Vec<_>>>()?;
+
+        Some(Self {
+            current_workspace: v["current_workspace"].as_u64()? as usize,
+            workspaces,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A state file no other test uses
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("nwm-test-{}-{name}.state", std::process::id()))
+    }
+
+    fn session() -> Session {
+        Session {
+            current_workspace: 2,
+            workspaces: vec![
+                SavedWorkspace {
+                    tiled: vec![0x400001, 0x400002],
+                    focused: Some(0x400002),
+                    floating: vec![(0x600001, (-20, 30, 640, 480))],
+                    layout: String::from("[]="),
+                },
+                SavedWorkspace {
+                    layout: String::from("[M]"),
+                    ..Default::default()
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn round_trips_through_the_state_file() {
+        let path = temp_path("round-trip");
+        session().save(&path).unwrap();
+        assert_eq!(Session::take(&path), Some(session()));
+    }
+
+    #[test]
+    fn take_removes_the_state_file() {
+        let path = temp_path("take");
+        session().save(&path).unwrap();
+        assert!(Session::take(&path).is_some());
+        assert!(!path.exists());
+        assert_eq!(Session::take(&path), None);
+    }
+
+    #[test]
+    fn rejects_invalid_state() {
+        let cases = [
+            "",
+            "not json",
+            "[]",
+            r#"{"workspaces": []}"#,
+            r#"{"current_workspace": 0}"#,
+            r#"{"current_workspace": -1, "workspaces": []}"#,
+            r#"{"current_workspace": 0, "workspaces": [{"tiled": [], "floating": []}]}"#,
+            r#"{"current_workspace": 0, "workspaces": [{"tiled": ["a"], "floating": [], "layout": "[]="}]}"#,
+            r#"{"current_workspace": 0, "workspaces": [{"tiled": [], "floating": [{"id": 1, "x": 0}], "layout": "[]="}]}"#,
+        ];
+        for state in cases {
+            let path = temp_path("invalid");
+            std::fs::write(&path, state).unwrap();
+            assert_eq!(Session::take(&path), None, "{state:?}");
+            assert!(!path.exists(), "{state:?}");
+        }
+    }
+}
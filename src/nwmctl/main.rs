@@ -0,0 +1,195 @@
+use std::{
+    io::{BufRead, BufReader, StdoutLock, Write},
+    os::unix::net::UnixStream,
+    path::PathBuf,
+    process::ExitCode,
+};
+
+const USAGE: &str = "\
+Usage: nwmctl <command> [args]
+
+Commands:
+  ws <n>                      Switch to workspace n (0-9)
+  move-to <n>                 Move the focused window to workspace n (0-9)
+  focus-left, focus-right     Focus the window to the left/right
+  move-left, move-right       Move the focused window to the left/right
+  next-ws, prev-ws            Switch to the next/previous workspace
+  next-layout, prev-layout    Cycle the layout of the current workspace
+  terminal, launcher          Launch the configured terminal/launcher
+  close                       Ask the focused window to close
+  kill                        Kill the program owning the focused window
+  fullscreen                  Toggle fullscreen of the focused window
+  reload                      Reload config.lua
+  restart                     Restart nwm in place, keeping every window where it is
+  quit                        Quit nwm
+  query workspaces [--json]   Print every workspace (index, current, layout, window count)
+  query tree                  Print the whole state of nwm as json
+  subscribe [events...]       Print one json line per event until nwm exits, every event if none
+                              are given (workspace, focus, window_open, window_close, title,
+                              layout, reload)
+
+Exit codes:
+  0  success
+  1  nwm refused the command
+  2  invalid usage
+  3  nwm isn't running or couldn't be reached";
+
+const EXIT_REFUSED: u8 = 1;
+const EXIT_USAGE: u8 = 2;
+const EXIT_UNREACHABLE: u8 = 3;
+
+/// Same lookup nwm does: `$NWM_SOCKET` or `$XDG_RUNTIME_DIR/nwm-$DISPLAY.sock`
+fn socket_path() -> Result<PathBuf, String> {
+    if let Ok(p) = std::env::var("NWM_SOCKET") {
+        return Ok(PathBuf::from(p));
+    }
+    let display = std::env::var("DISPLAY")
+        .map_err(|_| String::from("neither DISPLAY nor NWM_SOCKET is set"))?;
+    let dir = std::env::var("XDG_RUNTIME_DIR").unwrap_or_else(|_| String::from("/tmp"));
+    Ok(PathBuf::from(dir).join(format!("nwm-{display}.sock")))
+}
+
+fn connect() -> Result<UnixStream, (u8, String)> {
+    let path = socket_path().map_err(|e| (EXIT_UNREACHABLE, e))?;
+    UnixStream::connect(&path).map_err(|e| {
+        (
+            EXIT_UNREACHABLE,
+            format!(
+                "nwm is not running (can't connect to {}: {e})",
+                path.display()
+            ),
+        )
+    })
+}
+
+fn send(stream: &mut UnixStream, line: &str) -> Result<(), (u8, String)> {
+    stream
+        .write_all(format!("{line}\n").as_bytes())
+        .map_err(|e| (EXIT_UNREACHABLE, format!("failed to send the command: {e}")))
+}
+
+/// Reads one reply and returns its `data`
+fn read_reply(reader: &mut impl BufRead) -> Result<serde_json::Value, (u8, String)> {
+    let mut reply = String::new();
+    reader
+        .read_line(&mut reply)
+        .map_err(|e| (EXIT_UNREACHABLE, format!("failed to read the reply: {e}")))?;
+
+    let reply = serde_json::from_str::<serde_json::Value>(&reply)
+        .map_err(|e| (EXIT_UNREACHABLE, format!("nwm sent an invalid reply: {e}")))?;
+
+    if reply["ok"].as_bool() != Some(true) {
+        let error = reply["error"].as_str().unwrap_or("unknown error");
+        return Err((EXIT_REFUSED, error.to_string()));
+    }
+    Ok(reply["data"].clone())
+}
+
+/// Sends one command and returns the `data` of the reply
+fn request(line: &str) -> Result<serde_json::Value, (u8, String)> {
+    let mut stream = connect()?;
+    send(&mut stream, line)?;
+    read_reply(&mut BufReader::new(stream))
+}
+
+/// Prints every event nwm sends until either side goes away
+fn subscribe(events: &[&str]) -> Result<(), (u8, String)> {
+    let mut stream = connect()?;
+    let line = std::iter::once("subscribe").chain(events.iter().copied());
+    send(&mut stream, &line.collect::<Vec<_>>().join(" "))?;
+    let mut reader = BufReader::new(stream);
+    read_reply(&mut reader)?;
+
+    let mut stdout = std::io::stdout().lock();
+    for line in reader.lines() {
+        let line = line.map_err(|e| (EXIT_UNREACHABLE, format!("failed to read an event: {e}")))?;
+        // Whatever we were piped into stopped reading
+        if writeln!(stdout, "{line}")
+            .and_then(|_| stdout.flush())
+            .is_err()
+        {
+            return Ok(());
+        }
+    }
+    Err((EXIT_UNREACHABLE, String::from("nwm closed the connection")))
+}
+
+/// Writes to stdout. Whatever we were piped into going away (e.g. `| head`) isn't an error, the
+/// rest of the output is dropped
+fn print(write: impl FnOnce(&mut StdoutLock) -> std::io::Result<()>) {
+    let mut stdout = std::io::stdout().lock();
+    _ = write(&mut stdout).and_then(|()| stdout.flush());
+}
+
+fn print_workspaces(out: &mut impl Write, workspaces: &serde_json::Value) -> std::io::Result<()> {
+    for ws in workspaces.as_array().into_iter().flatten() {
+        let windows = ws["tiled"].as_array().map_or(0, |w| w.len())
+            + ws["floating"].as_array().map_or(0, |w| w.len());
+        writeln!(
+            out,
+            "{}\t{}\t{}\t{windows}",
+            ws["index"],
+            if ws["current"].as_bool() == Some(true) {
+                "*"
+            } else {
+                "-"
+            },
+            ws["layout"].as_str().unwrap_or_default(),
+        )?;
+    }
+    Ok(())
+}
+
+fn run(args: &[String]) -> Result<(), (u8, String)> {
+    let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+    match args.as_slice() {
+        [] => Err((EXIT_USAGE, String::from(USAGE))),
+        ["-h" | "--help" | "help"] => {
+            print(|out| writeln!(out, "{USAGE}"));
+            Ok(())
+        }
+        ["query", what, flags @ ..] => {
+            let json = match flags {
+                [] => false,
+                ["--json"] => true,
+                _ => return Err((EXIT_USAGE, format!("unexpected arguments {flags:?}"))),
+            };
+            match *what {
+                "workspaces" => {
+                    let data = request("get_workspaces")?;
+                    if json {
+                        print(|out| writeln!(out, "{data}"));
+                    } else {
+                        print(|out| print_workspaces(out, &data));
+                    }
+                    Ok(())
+                }
+                "tree" => {
+                    let data = request("get_tree")?;
+                    match serde_json::to_string_pretty(&data) {
+                        Ok(tree) if !json => print(|out| writeln!(out, "{tree}")),
+                        _ => print(|out| writeln!(out, "{data}")),
+                    }
+                    Ok(())
+                }
+                _ => Err((
+                    EXIT_USAGE,
+                    format!("can't query `{what}`, try workspaces or tree"),
+                )),
+            }
+        }
+        ["subscribe", events @ ..] => subscribe(events),
+        command => request(&command.join(" ")).map(|_| ()),
+    }
+}
+
+fn main() -> ExitCode {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err((code, message)) => {
+            eprintln!("nwmctl: {message}");
+            ExitCode::from(code)
+        }
+    }
+}